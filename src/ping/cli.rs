@@ -2,10 +2,13 @@ use std::{
     env,
     net::{AddrParseError, IpAddr, Ipv4Addr},
     num::ParseIntError,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use tokio::net;
 
+use ping_proxy::proto::{self, Payload, Probe, Vlan, MAX_IFACE, MAX_PATTERN, MAX_PAYLOAD_BYTES};
+
 #[derive(Debug)]
 pub struct CliArgumentError {
     kind: String,
@@ -73,11 +76,78 @@ impl From<CliArgumentError> for ParseError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Ping,
+    Traceroute,
+    Mtr,
+    Pmtu,
+    Capabilities,
+}
+
+/// When the next probe goes out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pace {
+    // every interval
+    Interval,
+    // as soon as the last reply is in, at least a hundred times a second
+    Flood,
+    // as soon as the last reply is in, at most every interval
+    Adaptive,
+    // exponentially distributed gaps averaging the interval
+    Poisson,
+    // on the multiples of the interval since the epoch
+    Aligned,
+}
+
+/// The state `--until-up` and `--until-down` wait for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+    Up,
+    Down,
+}
+
+/// Shortest interval without root, like iputils ping.
+const MIN_USER_INTERVAL: Duration = Duration::from_millis(200);
+/// Shortest interval `-i` takes, only a flood goes faster.
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+/// Longest interval.
+const MAX_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// A host to ping, as given and as resolved.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub name: String,
+    pub addr: IpAddr,
+}
+
+#[derive(Debug, Clone)]
 pub struct CliArgs {
+    pub mode: Mode,
+    pub pace: Pace,
+    pub probe: Probe,
+    pub max_hops: u8,
+    pub queries: u8,
+    pub dest_port: u16,
+    pub report: Option<String>,
+    pub tos: Option<u8>,
+    pub payload: Payload,
+    pub iface: Option<String>,
+    pub unicast: bool,
+    pub vlan: Option<Vlan>,
+    pub netns: Option<String>,
+    pub multi: bool,
+    pub show_clock: bool,
     pub show_error: bool,
     pub quiet: bool,
-    pub interval: u8,
+    pub interval: Duration,
+    // seeds the poisson gaps
+    pub seed: u64,
+    // how long the whole run may take
+    pub deadline: Option<Duration>,
+    pub until: Option<Until>,
+    // consecutive probes that make the until state
+    pub hysteresis: u32,
     pub length: u16,
     pub port: u16,
     pub timeout: u16,
@@ -85,39 +155,185 @@ pub struct CliArgs {
     pub proxy: IpAddr,
     pub host_addr: IpAddr,
     pub host_name: String,
+    // every host to ping, the first is `host_addr`
+    pub targets: Vec<Target>,
+    // one of several targets pinged at once, a table sums them up
+    pub many: bool,
 }
 
 impl CliArgs {
     pub fn new() -> Self {
         CliArgs {
+            mode: Mode::Ping,
+            pace: Pace::Interval,
+            probe: Probe::Echo,
+            max_hops: 30,
+            queries: 3,
+            dest_port: 0,
+            report: None,
+            tos: None,
+            payload: Payload::Sequence,
+            iface: None,
+            unicast: false,
+            vlan: None,
+            netns: None,
+            multi: false,
+            show_clock: false,
             show_error: false,
             quiet: false,
-            interval: 1,
-            length: 64,
+            interval: Duration::from_secs(1),
+            seed: 0,
+            deadline: None,
+            until: None,
+            hysteresis: 1,
+            length: 56,
             port: 2000,
             timeout: 4000,
             count: u32::MAX,
             proxy: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
             host_addr: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
             host_name: String::new(),
+            targets: Vec::new(),
+            many: false,
+        }
+    }
+
+    /// The arguments to ping one of several targets with.
+    pub fn for_target(&self, target: &Target) -> CliArgs {
+        let mut args = self.clone();
+        args.host_name = target.name.clone();
+        args.host_addr = target.addr;
+        args.targets = Vec::new();
+        args.many = true;
+        // every member of a multicast group answers
+        args.multi = self.multi || target.addr.is_multicast();
+        args
+    }
+
+    /// IP and ICMP or UDP header bytes in front of the payload.
+    pub fn header_len(&self) -> u16 {
+        if self.host_addr.is_ipv4() {
+            20 + 8
+        } else {
+            40 + 8
         }
     }
 }
 
 fn usage() {
-    println!("Usage: ping [options] host");
+    println!("Usage: ping [options] host...");
+    println!("  -b    allow pinging a broadcast address, every responder is listed");
     println!("  -c    ping count");
     println!("  -e    show error reason");
-    println!("  -i    interval time (secs), default 1");
-    println!("  -l    packet length");
+    println!("  -f    flood ping, a dot per probe sent and a backspace per reply");
+    println!("  -A    adaptive ping, the interval follows the round trip time");
+    println!("  -i    interval time (secs), down to 0.001, default 1");
+    println!("  -l    payload size, default 56");
     println!("  -r    proxy remote address");
     println!("  -p    proxy remote port");
     println!("  -q    quiet output");
+    println!("  -Q    tos or traffic class, decimal or 0x hex");
+    println!("  -P    payload pattern, up to 16 hex bytes");
+    println!("  -I    proxy interface for link layer probes");
     println!("  -t    ping timeout (millis), default 4000");
+    println!("  -w    deadline (secs), stop after this long whatever the count");
+    println!("  -m    traceroute max hops, default 30");
+    println!("  --traceroute  trace the route to host");
+    println!("  --probe       traceroute probe icmp|udp|tcp, default icmp");
+    println!("  --queries     traceroute probes per hop, default 3");
+    println!("  --port        traceroute udp/tcp destination port");
+    println!("  --tcp-port    ping a TCP port, open, closed or filtered");
+    println!("  --udp-port    ping a UDP port, answered, closed or no answer");
+    println!("  --coap        CoAP ping a device, --port for other than 5683");
+    println!("  --timestamp   ICMP timestamp requests, shows the host's clock offset");
+    println!("  --mtr         probe every hop to host continuously");
+    println!("  --report      mtr final report file");
+    println!("  --pmtu        discover the path mtu to host");
+    println!("  --probes      list the probe types the proxy supports");
+    println!("  --clock       show the clock each time was measured with");
+    println!("  --payload     zeros|random[:SEED]|hex:BYTES|file:PATH");
+    println!("  --arp         ARP ping a host on the proxy's segment");
+    println!("  --ndp         neighbor solicit an IPv6 host on the proxy's segment");
+    println!("  --unicast     solicit the host itself, not its multicast group");
+    println!("  --vlan        ID[:PRIORITY], send 802.1Q tagged echo or timestamp requests");
+    println!("  --netns       probe from one of the proxy's network namespaces");
+    println!("  --file        more hosts to ping, one a line, - reads stdin");
+    println!("  --poisson     random gaps averaging the interval, for unbiased sampling");
+    println!("  --seed        seed of the poisson gaps, from the clock by default");
+    println!("  --align       send on multiples of the interval of the wall clock");
+    println!("  --until-up    ping until the host answers, exit 0 once it does");
+    println!("  --until-down  ping until the host stops answering, exit 0 once it does");
+    println!("  --hysteresis  consecutive probes that make up or down, default 1");
     println!("  -v    version");
     println!("  -h    help");
 }
 
+fn hex_parse(value: &str) -> Result<Vec<u8>, ParseError> {
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    if value.is_empty() || !value.is_ascii() || !value.len().is_multiple_of(2) {
+        return Err(ParseError::Argument(CliArgumentError::new(
+            "invalid hex bytes",
+        )));
+    }
+
+    let mut bytes = Vec::new();
+    for i in (0..value.len()).step_by(2) {
+        bytes.push(u8::from_str_radix(&value[i..i + 2], 16)?);
+    }
+    Ok(bytes)
+}
+
+/// Seconds, to the millisecond.
+fn interval_parse(value: &str) -> Result<Duration, ParseError> {
+    let secs = value.parse::<f64>().unwrap_or(f64::NAN);
+    if !(MIN_INTERVAL.as_secs_f64()..=MAX_INTERVAL.as_secs_f64()).contains(&secs) {
+        let err = CliArgumentError::new("invalid interval");
+        return Err(ParseError::Argument(err));
+    }
+    Ok(Duration::from_millis((secs * 1000.0).round() as u64))
+}
+
+fn payload_parse(value: &str) -> Result<Payload, ParseError> {
+    let (mode, arg) = match value.split_once(':') {
+        Some((mode, arg)) => (mode, Some(arg)),
+        None => (value, None),
+    };
+
+    let bytes = match (mode, arg) {
+        ("zeros", None) => return Ok(Payload::Zeros),
+        ("random", None) => return Ok(Payload::Random(0)),
+        ("random", Some(seed)) => return Ok(Payload::Random(seed.parse::<u64>()?)),
+        ("hex", Some(hex)) => hex_parse(hex)?,
+        ("file", Some(path)) => std::fs::read(path).map_err(|err| {
+            let msg = format!("read payload {}: {}", path, err);
+            ParseError::Argument(CliArgumentError::new(&msg))
+        })?,
+        _ => {
+            let err = CliArgumentError::new("invalid payload");
+            return Err(ParseError::Argument(err));
+        }
+    };
+
+    if bytes.len() > MAX_PAYLOAD_BYTES {
+        let msg = format!("payload longer than {} bytes", MAX_PAYLOAD_BYTES);
+        return Err(ParseError::Argument(CliArgumentError::new(&msg)));
+    }
+    Ok(Payload::Bytes(bytes))
+}
+
+fn vlan_parse(value: &str) -> Result<Vlan, ParseError> {
+    let (id, pcp) = match value.split_once(':') {
+        Some((id, pcp)) => (id, pcp.parse::<u8>()?),
+        None => (value, 0),
+    };
+    let id = id.parse::<u16>()?;
+    if id == 0 || id > Vlan::MAX_ID || pcp > 7 {
+        let err = CliArgumentError::new("invalid vlan, id 1-4094 and priority 0-7");
+        return Err(ParseError::Argument(err));
+    }
+    Ok(Vlan { id, pcp })
+}
+
 fn value_check(value: Option<&String>) -> Result<&String, CliArgumentError> {
     match value {
         Some(v) => Ok(v),
@@ -126,19 +342,25 @@ fn value_check(value: Option<&String>) -> Result<&String, CliArgumentError> {
 }
 
 pub async fn parse() -> Result<CliArgs, ParseError> {
-    let args: Vec<String> = env::args().skip(1).collect();
+    parse_args(env::args().skip(1).collect()).await
+}
 
+async fn parse_args(args: Vec<String>) -> Result<CliArgs, ParseError> {
     if args.is_empty() {
         let err = CliArgumentError::new("no host specified");
         return Err(ParseError::Argument(err));
     }
 
     let mut cli_args = CliArgs::new();
+    let mut interval_given = false;
+    let mut seed = None;
+    let mut names = Vec::new();
+    let mut file_names = Vec::new();
     let mut iter = args.iter();
     while let Some(key) = iter.next() {
         let key = key.as_str();
         if key.starts_with('-') {
-            if !cli_args.host_addr.is_unspecified() {
+            if !names.is_empty() {
                 let err = CliArgumentError::new("invalid option order");
                 return Err(ParseError::Argument(err));
             }
@@ -148,16 +370,73 @@ pub async fn parse() -> Result<CliArgs, ParseError> {
                     let value = value_check(iter.next())?;
                     cli_args.count = value.parse::<u32>()?;
                 }
+                "-b" => {
+                    cli_args.multi = true;
+                }
                 "-e" => {
                     cli_args.show_error = true;
                 }
+                "-f" | "-A" | "--poisson" | "--align" => {
+                    let pace = match key {
+                        "-f" => Pace::Flood,
+                        "-A" => Pace::Adaptive,
+                        "--poisson" => Pace::Poisson,
+                        _ => Pace::Aligned,
+                    };
+                    if cli_args.pace != Pace::Interval && cli_args.pace != pace {
+                        let msg = "-f, -A, --poisson and --align do not go together";
+                        let err = CliArgumentError::new(msg);
+                        return Err(ParseError::Argument(err));
+                    }
+                    cli_args.pace = pace;
+                }
                 "-l" => {
                     let value = value_check(iter.next())?;
                     cli_args.length = value.parse::<u16>()?;
                 }
                 "-i" => {
                     let value = value_check(iter.next())?;
-                    cli_args.interval = value.parse::<u8>()?;
+                    cli_args.interval = interval_parse(value)?;
+                    interval_given = true;
+                }
+                "-w" => {
+                    let value = value_check(iter.next())?;
+                    let secs = value.parse::<f64>().unwrap_or(f64::NAN);
+                    match Duration::try_from_secs_f64(secs) {
+                        Ok(deadline) if !deadline.is_zero() => cli_args.deadline = Some(deadline),
+                        _ => {
+                            let err = CliArgumentError::new("invalid deadline");
+                            return Err(ParseError::Argument(err));
+                        }
+                    }
+                }
+                "--until-up" | "--until-down" => {
+                    let until = if key == "--until-up" {
+                        Until::Up
+                    } else {
+                        Until::Down
+                    };
+                    if cli_args.until.is_some_and(|given| given != until) {
+                        let err = CliArgumentError::new("--until-up or --until-down, not both");
+                        return Err(ParseError::Argument(err));
+                    }
+                    cli_args.until = Some(until);
+                }
+                "--hysteresis" => {
+                    let value = value_check(iter.next())?;
+                    cli_args.hysteresis = value.parse::<u32>()?;
+                    if cli_args.hysteresis == 0 {
+                        let err = CliArgumentError::new("invalid hysteresis");
+                        return Err(ParseError::Argument(err));
+                    }
+                }
+                "--file" => {
+                    let value = value_check(iter.next())?;
+                    file_names.extend(hosts_read(value)?);
+                }
+                "--seed" => {
+                    let value = value_check(iter.next())?;
+                    seed = Some(value.parse::<u64>()?);
                 }
                 "-r" => {
                     let value = value_check(iter.next())?;
@@ -184,10 +463,130 @@ pub async fn parse() -> Result<CliArgs, ParseError> {
                 "-q" => {
                     cli_args.quiet = true;
                 }
+                "-Q" => {
+                    let value = value_check(iter.next())?;
+                    let tos = match value.strip_prefix("0x") {
+                        Some(hex) => u8::from_str_radix(hex, 16)?,
+                        None => value.parse::<u8>()?,
+                    };
+                    cli_args.tos = Some(tos);
+                }
+                "-P" => {
+                    let value = value_check(iter.next())?;
+                    let pattern = hex_parse(value)?;
+                    if pattern.len() > MAX_PATTERN {
+                        let err = CliArgumentError::new("pattern longer than 16 bytes");
+                        return Err(ParseError::Argument(err));
+                    }
+                    cli_args.payload = Payload::Pattern(pattern);
+                }
+                "-I" => {
+                    let value = value_check(iter.next())?;
+                    if value.is_empty() || value.len() > MAX_IFACE {
+                        let err = CliArgumentError::new("invalid interface");
+                        return Err(ParseError::Argument(err));
+                    }
+                    cli_args.iface = Some(value.clone());
+                }
+                "--arp" => {
+                    cli_args.probe = Probe::Arp;
+                }
+                "--ndp" => {
+                    cli_args.probe = Probe::Ndp;
+                }
+                "--coap" => {
+                    cli_args.probe = Probe::Coap;
+                }
+                "--timestamp" => {
+                    cli_args.probe = Probe::Timestamp;
+                }
+                "--unicast" => {
+                    cli_args.unicast = true;
+                }
+                "--vlan" => {
+                    let value = value_check(iter.next())?;
+                    cli_args.vlan = Some(vlan_parse(value)?);
+                }
+                "--netns" => {
+                    let value = value_check(iter.next())?;
+                    if !proto::netns_valid(value) {
+                        let err = CliArgumentError::new("invalid namespace");
+                        return Err(ParseError::Argument(err));
+                    }
+                    cli_args.netns = Some(value.clone());
+                }
+                "--payload" => {
+                    let value = value_check(iter.next())?;
+                    cli_args.payload = payload_parse(value)?;
+                }
                 "-t" => {
                     let value = value_check(iter.next())?;
                     cli_args.timeout = value.parse::<u16>()?;
                 }
+                "-m" => {
+                    let value = value_check(iter.next())?;
+                    cli_args.max_hops = value.parse::<u8>()?;
+                    if cli_args.max_hops == 0 {
+                        let err = CliArgumentError::new("invalid max hops");
+                        return Err(ParseError::Argument(err));
+                    }
+                }
+                "--traceroute" => {
+                    cli_args.mode = Mode::Traceroute;
+                }
+                "--mtr" => {
+                    cli_args.mode = Mode::Mtr;
+                }
+                "--pmtu" => {
+                    cli_args.mode = Mode::Pmtu;
+                }
+                "--probes" => {
+                    cli_args.mode = Mode::Capabilities;
+                }
+                "--clock" => {
+                    cli_args.show_clock = true;
+                }
+                "--report" => {
+                    let value = value_check(iter.next())?;
+                    cli_args.report = Some(value.clone());
+                }
+                "--probe" => {
+                    let value = value_check(iter.next())?;
+                    cli_args.probe = match value.as_str() {
+                        "icmp" => Probe::Echo,
+                        "udp" => Probe::Udp,
+                        "tcp" => Probe::Tcp,
+                        _ => {
+                            let err = CliArgumentError::new("invalid probe type");
+                            return Err(ParseError::Argument(err));
+                        }
+                    };
+                }
+                "--tcp-port" | "--udp-port" => {
+                    let value = value_check(iter.next())?;
+                    cli_args.dest_port = value.parse::<u16>()?;
+                    if cli_args.dest_port == 0 {
+                        let err = CliArgumentError::new("invalid port");
+                        return Err(ParseError::Argument(err));
+                    }
+                    cli_args.probe = if key == "--tcp-port" {
+                        Probe::Tcp
+                    } else {
+                        Probe::Udp
+                    };
+                }
+                "--queries" => {
+                    let value = value_check(iter.next())?;
+                    cli_args.queries = value.parse::<u8>()?;
+                    if cli_args.queries == 0 {
+                        let err = CliArgumentError::new("invalid queries");
+                        return Err(ParseError::Argument(err));
+                    }
+                }
+                "--port" => {
+                    let value = value_check(iter.next())?;
+                    cli_args.dest_port = value.parse::<u16>()?;
+                }
                 "-v" => {
                     println!("version 0.1.0");
                     std::process::exit(0);
@@ -201,30 +600,570 @@ pub async fn parse() -> Result<CliArgs, ParseError> {
                     return Err(ParseError::Argument(err));
                 }
             }
-        } else if cli_args.host_addr.is_unspecified() {
-            if let Ok(addr) = key.parse::<IpAddr>() {
-                cli_args.host_addr = addr;
-            } else {
-                let host = format!("{}:0", key);
-                if let Ok(mut iter) = net::lookup_host(host).await {
-                    cli_args.host_addr = iter.next().unwrap().ip();
-                } else {
-                    let err = CliArgumentError::new("invalid host");
-                    return Err(ParseError::Argument(err));
-                }
-            }
-
-            cli_args.host_name.push_str(key);
         } else {
-            let err = CliArgumentError::new("already specified host");
-            return Err(ParseError::Argument(err));
+            names.push(key.to_string());
         }
     }
 
-    if cli_args.host_addr.is_unspecified() {
+    if cli_args.mode == Mode::Capabilities {
+        return Ok(cli_args);
+    }
+    // only hosts on the command line end the options
+    names.extend(file_names);
+    if names.is_empty() {
         let err = CliArgumentError::new("no host specified");
         return Err(ParseError::Argument(err));
     }
+    if names.len() > 1 && cli_args.mode != Mode::Ping {
+        let err = CliArgumentError::new("only ping takes several hosts");
+        return Err(ParseError::Argument(err));
+    }
+
+    for name in names {
+        let addr = host_resolve(&name).await?;
+        host_check(&cli_args, &addr)?;
+        cli_args.targets.push(Target { name, addr });
+    }
+    cli_args.host_name = cli_args.targets[0].name.clone();
+    cli_args.host_addr = cli_args.targets[0].addr;
+    // every member of a multicast group answers
+    if cli_args.targets.len() == 1 && cli_args.host_addr.is_multicast() {
+        cli_args.multi = true;
+    }
+    if cli_args.multi && !matches!(cli_args.probe, Probe::Echo | Probe::Timestamp) {
+        let err = CliArgumentError::new("broadcast and multicast need icmp echo or timestamp");
+        return Err(ParseError::Argument(err));
+    }
+    if cli_args.multi && cli_args.mode != Mode::Ping {
+        let err = CliArgumentError::new("broadcast and multicast only work in ping mode");
+        return Err(ParseError::Argument(err));
+    }
+    let stops = cli_args.deadline.is_some() || cli_args.until.is_some();
+    if stops && cli_args.mode != Mode::Ping {
+        let err = CliArgumentError::new("-w, --until-up and --until-down only work in ping mode");
+        return Err(ParseError::Argument(err));
+    }
+    if cli_args.pace != Pace::Interval && cli_args.mode != Mode::Ping {
+        let err = CliArgumentError::new("flood and adaptive only work in ping mode");
+        return Err(ParseError::Argument(err));
+    }
+    // a flood goes as fast as the replies unless given a rate
+    if cli_args.pace == Pace::Flood && !interval_given {
+        cli_args.interval = Duration::ZERO;
+    }
+    let root = unsafe { libc::geteuid() } == 0;
+    if cli_args.interval < MIN_USER_INTERVAL && !root {
+        let msg = if cli_args.interval.is_zero() {
+            format!(
+                "a flood needs root, or -i {} or more",
+                MIN_USER_INTERVAL.as_secs_f64()
+            )
+        } else {
+            format!(
+                "intervals under {} ms need root",
+                MIN_USER_INTERVAL.as_millis()
+            )
+        };
+        return Err(ParseError::Argument(CliArgumentError::new(&msg)));
+    }
+    cli_args.seed = seed.unwrap_or_else(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        now.as_nanos() as u64
+    });
+    if cli_args.vlan.is_some() && !matches!(cli_args.probe, Probe::Echo | Probe::Timestamp) {
+        let err = CliArgumentError::new("vlan tags only go on icmp echo or timestamp probes");
+        return Err(ParseError::Argument(err));
+    }
+
+    // the ports the proxy probes when the request has none
+    if cli_args.mode == Mode::Ping && cli_args.dest_port == 0 {
+        match cli_args.probe {
+            Probe::Tcp => cli_args.dest_port = 80,
+            Probe::Udp => cli_args.dest_port = 33434,
+            Probe::Coap => cli_args.dest_port = 5683,
+            _ => {}
+        }
+    }
 
     Ok(cli_args)
 }
+
+async fn host_resolve(name: &str) -> Result<IpAddr, ParseError> {
+    if let Ok(addr) = name.parse::<IpAddr>() {
+        return Ok(addr);
+    }
+    let host = format!("{}:0", name);
+    match net::lookup_host(host).await.map(|mut iter| iter.next()) {
+        Ok(Some(addr)) => Ok(addr.ip()),
+        _ => {
+            let msg = format!("invalid host {}", name);
+            Err(ParseError::Argument(CliArgumentError::new(&msg)))
+        }
+    }
+}
+
+/// What the probe and the payload need of a host.
+fn host_check(cli_args: &CliArgs, addr: &IpAddr) -> Result<(), ParseError> {
+    let msg = match cli_args.probe {
+        Probe::Arp if !addr.is_ipv4() => "arp needs an IPv4 host",
+        Probe::Timestamp if !addr.is_ipv4() => "timestamp needs an IPv4 host",
+        Probe::Ndp if !addr.is_ipv6() => "ndp needs an IPv6 host",
+        Probe::Echo | Probe::Timestamp => "",
+        _ if addr.is_multicast() => "broadcast and multicast need icmp echo or timestamp",
+        _ => "",
+    };
+    if !msg.is_empty() {
+        return Err(ParseError::Argument(CliArgumentError::new(msg)));
+    }
+    if addr.is_multicast() && cli_args.mode != Mode::Ping {
+        let err = CliArgumentError::new("broadcast and multicast only work in ping mode");
+        return Err(ParseError::Argument(err));
+    }
+
+    let max_length = proto::max_length(addr);
+    if cli_args.length > max_length {
+        let msg = format!("payload size {} over {}", cli_args.length, max_length);
+        return Err(ParseError::Argument(CliArgumentError::new(&msg)));
+    }
+    Ok(())
+}
+
+/// Host names, one a line of `path` or stdin for `-`, blank lines and
+/// `#` comments are skipped.
+fn hosts_read(path: &str) -> Result<Vec<String>, ParseError> {
+    let text = if path == "-" {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(path)
+    };
+    let text = text.map_err(|err| {
+        let msg = format!("read hosts {}: {}", path, err);
+        ParseError::Argument(CliArgumentError::new(&msg))
+    })?;
+
+    let hosts = text
+        .lines()
+        .filter_map(|line| line.split('#').next()?.split_whitespace().next())
+        .map(|host| host.to_string())
+        .collect();
+    Ok(hosts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse_line(line: &str) -> Result<CliArgs, String> {
+        let args = line.split_whitespace().map(|arg| arg.to_string()).collect();
+        parse_args(args).await.map_err(|err| err.to_string())
+    }
+
+    #[tokio::test]
+    async fn options_then_host() {
+        let cli_args = parse_line("-c 3 -l 100 -t 500 -q 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.count, 3);
+        assert_eq!(cli_args.length, 100);
+        assert_eq!(cli_args.timeout, 500);
+        assert!(cli_args.quiet);
+        assert_eq!(cli_args.host_name, "10.0.0.1");
+        assert_eq!(cli_args.host_addr, "10.0.0.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn option_order() {
+        assert_eq!(
+            parse_line("10.0.0.1 -c 3").await.unwrap_err(),
+            "Invalid argument: invalid option order"
+        );
+        assert_eq!(
+            parse_line("").await.unwrap_err(),
+            "Invalid argument: no host specified"
+        );
+        assert_eq!(
+            parse_line("-c").await.unwrap_err(),
+            "Invalid argument: Miss arguments"
+        );
+        assert_eq!(
+            parse_line("-x 10.0.0.1").await.unwrap_err(),
+            "Invalid argument: unknown option"
+        );
+    }
+
+    #[tokio::test]
+    async fn several_hosts() {
+        let cli_args = parse_line("-c 2 10.0.0.1 fd00::1").await.unwrap();
+        assert_eq!(names(&cli_args), vec!["10.0.0.1", "fd00::1"]);
+        assert_eq!(cli_args.host_name, "10.0.0.1");
+
+        // each host on its own, a multicast one among many answers as a group
+        let target = cli_args.targets[1].clone();
+        let args = cli_args.for_target(&target);
+        assert_eq!(args.host_addr, target.addr);
+        assert!(args.many && !args.multi);
+        let group = Target {
+            name: "ff02::1".to_string(),
+            addr: "ff02::1".parse().unwrap(),
+        };
+        assert!(cli_args.for_target(&group).multi);
+
+        assert_eq!(
+            parse_line("--traceroute 10.0.0.1 10.0.0.2")
+                .await
+                .unwrap_err(),
+            "Invalid argument: only ping takes several hosts"
+        );
+        assert!(parse_line("--arp 10.0.0.1 fd00::1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn traceroute() {
+        let line = "--traceroute --probe udp -m 5 --queries 2 --port 33434 10.0.0.1";
+        let cli_args = parse_line(line).await.unwrap();
+        assert_eq!(cli_args.mode, Mode::Traceroute);
+        assert_eq!(cli_args.probe, Probe::Udp);
+        assert_eq!(cli_args.max_hops, 5);
+        assert_eq!(cli_args.queries, 2);
+        assert_eq!(cli_args.dest_port, 33434);
+
+        assert!(parse_line("--probe sctp 10.0.0.1").await.is_err());
+        assert!(parse_line("-m 0 10.0.0.1").await.is_err());
+        assert!(parse_line("--queries 0 10.0.0.1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn mtr() {
+        let cli_args = parse_line("--mtr --report mtr.txt -c 5 10.0.0.1")
+            .await
+            .unwrap();
+        assert_eq!(cli_args.mode, Mode::Mtr);
+        assert_eq!(cli_args.report.as_deref(), Some("mtr.txt"));
+        assert_eq!(cli_args.count, 5);
+    }
+
+    #[tokio::test]
+    async fn tos() {
+        let cli_args = parse_line("-Q 0xb8 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.tos, Some(0xb8));
+        let cli_args = parse_line("-Q 16 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.tos, Some(16));
+
+        assert!(parse_line("-Q 256 10.0.0.1").await.is_err());
+        assert!(parse_line("-Q 0x1ff 10.0.0.1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pmtu() {
+        let cli_args = parse_line("--pmtu fd00::1").await.unwrap();
+        assert_eq!(cli_args.mode, Mode::Pmtu);
+    }
+
+    #[tokio::test]
+    async fn clock() {
+        let cli_args = parse_line("--clock 10.0.0.1").await.unwrap();
+        assert!(cli_args.show_clock);
+    }
+
+    /// A file in the temp directory, removed when dropped.
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, text: &str) -> Self {
+            let path = env::temp_dir().join(format!("ping-{}-{}", std::process::id(), name));
+            std::fs::write(&path, text).unwrap();
+            TempFile(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(
+            hex_parse("0xdeadBEEF").unwrap(),
+            vec![0xde, 0xad, 0xbe, 0xef]
+        );
+        assert_eq!(hex_parse("00").unwrap(), vec![0]);
+        for bad in ["", "0x", "abc", "zz", "\u{e9}a"] {
+            assert!(hex_parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn payload() {
+        assert_eq!(payload_parse("zeros").unwrap(), Payload::Zeros);
+        assert_eq!(payload_parse("random").unwrap(), Payload::Random(0));
+        assert_eq!(payload_parse("random:7").unwrap(), Payload::Random(7));
+        assert_eq!(
+            payload_parse("hex:0102").unwrap(),
+            Payload::Bytes(vec![1, 2])
+        );
+
+        let file = TempFile::new("payload", "abc");
+        let value = format!("file:{}", file.path());
+        assert_eq!(
+            payload_parse(&value).unwrap(),
+            Payload::Bytes(b"abc".to_vec())
+        );
+
+        let long = format!("hex:{}", "00".repeat(MAX_PAYLOAD_BYTES + 1));
+        for bad in [
+            "",
+            "zeros:1",
+            "random:x",
+            "hex",
+            "hex:1",
+            "file:/nonexistent",
+            "ones",
+            &long,
+        ] {
+            assert!(payload_parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn pattern() {
+        let cli_args = parse_line("-P 0xdead 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.payload, Payload::Pattern(vec![0xde, 0xad]));
+        let cli_args = parse_line("--payload random:3 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.payload, Payload::Random(3));
+
+        let long = format!("-P {} 10.0.0.1", "00".repeat(MAX_PATTERN + 1));
+        assert!(parse_line(&long).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn length() {
+        assert!(parse_line("-l 65507 10.0.0.1").await.is_ok());
+        assert!(parse_line("-l 65527 fd00::1").await.is_ok());
+        assert_eq!(
+            parse_line("-l 65508 10.0.0.1").await.unwrap_err(),
+            "Invalid argument: payload size 65508 over 65507"
+        );
+    }
+
+    #[tokio::test]
+    async fn arp() {
+        let cli_args = parse_line("--arp -I eth0 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.probe, Probe::Arp);
+        assert_eq!(cli_args.iface.as_deref(), Some("eth0"));
+
+        assert!(parse_line("--arp fd00::1").await.is_err());
+        assert!(parse_line("--arp -I interface-name-1 10.0.0.1")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn ndp() {
+        let cli_args = parse_line("--ndp --unicast fe80::1").await.unwrap();
+        assert_eq!(cli_args.probe, Probe::Ndp);
+        assert!(cli_args.unicast);
+
+        assert!(parse_line("--ndp 10.0.0.1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn vlan() {
+        assert_eq!(vlan_parse("10").unwrap(), Vlan { id: 10, pcp: 0 });
+        assert_eq!(vlan_parse("4094:7").unwrap(), Vlan { id: 4094, pcp: 7 });
+        for bad in ["", "0", "4095", "10:8", "x", "10:", ":3"] {
+            assert!(vlan_parse(bad).is_err(), "{}", bad);
+        }
+
+        let cli_args = parse_line("--vlan 10:3 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.vlan, Some(Vlan { id: 10, pcp: 3 }));
+        let cli_args = parse_line("--timestamp --vlan 10 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.vlan, Some(Vlan { id: 10, pcp: 0 }));
+        assert!(parse_line("--probe udp --vlan 10 10.0.0.1").await.is_err());
+        assert!(parse_line("--arp --vlan 10 10.0.0.1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn netns() {
+        let cli_args = parse_line("--netns blue 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.netns.as_deref(), Some("blue"));
+
+        assert!(parse_line("--netns .. 10.0.0.1").await.is_err());
+        assert!(parse_line("--netns a/b 10.0.0.1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tcp_port() {
+        let cli_args = parse_line("--tcp-port 443 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.probe, Probe::Tcp);
+        assert_eq!(cli_args.dest_port, 443);
+        // the port the proxy connects to when none is given
+        let cli_args = parse_line("--probe tcp 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.dest_port, 80);
+
+        assert!(parse_line("--tcp-port 0 10.0.0.1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_port() {
+        let cli_args = parse_line("--udp-port 53 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.probe, Probe::Udp);
+        assert_eq!(cli_args.dest_port, 53);
+        let cli_args = parse_line("--probe udp 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.dest_port, 33434);
+
+        assert!(parse_line("--udp-port 0 10.0.0.1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn coap() {
+        let cli_args = parse_line("--coap 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.probe, Probe::Coap);
+        assert_eq!(cli_args.dest_port, 5683);
+        let cli_args = parse_line("--coap --port 5684 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.dest_port, 5684);
+    }
+
+    #[tokio::test]
+    async fn capabilities() {
+        // the proxy is asked, no host needed
+        let cli_args = parse_line("--probes").await.unwrap();
+        assert_eq!(cli_args.mode, Mode::Capabilities);
+    }
+
+    #[tokio::test]
+    async fn timestamp() {
+        let cli_args = parse_line("--timestamp 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.probe, Probe::Timestamp);
+
+        assert!(parse_line("--timestamp fd00::1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn multicast() {
+        let cli_args = parse_line("ff02::1").await.unwrap();
+        assert!(cli_args.multi);
+        let cli_args = parse_line("-b 10.0.0.255").await.unwrap();
+        assert!(cli_args.multi);
+        let cli_args = parse_line("--timestamp -b 10.0.0.255").await.unwrap();
+        assert!(cli_args.multi);
+        assert!(!parse_line("10.0.0.1").await.unwrap().multi);
+
+        assert!(parse_line("--tcp-port 80 ff02::1").await.is_err());
+        assert!(parse_line("-b --mtr 10.0.0.255").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pace() {
+        assert_eq!(parse_line("-f 10.0.0.1").await.unwrap().pace, Pace::Flood);
+        assert_eq!(
+            parse_line("-A 10.0.0.1").await.unwrap().pace,
+            Pace::Adaptive
+        );
+        assert_eq!(
+            parse_line("-f -f 10.0.0.1").await.unwrap().pace,
+            Pace::Flood
+        );
+
+        assert!(parse_line("-f -A 10.0.0.1").await.is_err());
+        assert!(parse_line("-A --traceroute 10.0.0.1").await.is_err());
+    }
+
+    #[test]
+    fn interval() {
+        assert_eq!(interval_parse("0.2").unwrap(), Duration::from_millis(200));
+        assert_eq!(interval_parse("2").unwrap(), Duration::from_secs(2));
+        assert_eq!(interval_parse("0.0015").unwrap(), Duration::from_millis(2));
+        assert_eq!(interval_parse("0.001").unwrap(), MIN_INTERVAL);
+        assert_eq!(interval_parse("3600").unwrap(), MAX_INTERVAL);
+        for bad in ["", "x", "-1", "0", "0.0004", "NaN", "inf", "3600.5"] {
+            assert!(interval_parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn schedules() {
+        // a flood goes as fast as the replies unless given a rate
+        let cli_args = parse_line("-f 10.0.0.1").await.unwrap();
+        assert_eq!(cli_args.interval, Duration::ZERO);
+
+        let cli_args = parse_line("--poisson --seed 9 -i 2 10.0.0.1")
+            .await
+            .unwrap();
+        assert_eq!(cli_args.pace, Pace::Poisson);
+        assert_eq!(cli_args.seed, 9);
+        assert_eq!(cli_args.interval, Duration::from_secs(2));
+        assert_eq!(
+            parse_line("--align 10.0.0.1").await.unwrap().pace,
+            Pace::Aligned
+        );
+
+        assert!(parse_line("--poisson --align 10.0.0.1").await.is_err());
+        assert!(parse_line("--align -i 0 10.0.0.1").await.is_err());
+        assert!(parse_line("--poisson --mtr 10.0.0.1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn until() {
+        let cli_args = parse_line("--until-up --hysteresis 3 -w 2.5 10.0.0.1")
+            .await
+            .unwrap();
+        assert_eq!(cli_args.until, Some(Until::Up));
+        assert_eq!(cli_args.hysteresis, 3);
+        assert_eq!(cli_args.deadline, Some(Duration::from_millis(2500)));
+
+        assert!(parse_line("--until-up --until-down 10.0.0.1")
+            .await
+            .is_err());
+        assert!(parse_line("--hysteresis 0 10.0.0.1").await.is_err());
+        assert!(parse_line("-w 0 10.0.0.1").await.is_err());
+        assert!(parse_line("-w -1 10.0.0.1").await.is_err());
+        assert!(parse_line("--until-down --pmtu 10.0.0.1").await.is_err());
+    }
+
+    fn names(cli_args: &CliArgs) -> Vec<&str> {
+        cli_args
+            .targets
+            .iter()
+            .map(|target| target.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn hosts() {
+        let text = "10.0.0.1\n\n  # a comment\n10.0.0.2 # second\n\t10.0.0.3 extra\n";
+        let file = TempFile::new("hosts", text);
+        assert_eq!(
+            hosts_read(file.path()).unwrap(),
+            vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+        );
+        assert!(hosts_read("/nonexistent").is_err());
+    }
+
+    #[tokio::test]
+    async fn file_then_options() {
+        let file = TempFile::new("order", "10.0.0.2\n10.0.0.3\n");
+
+        // options may follow --file, only a host on the command line ends them
+        let line = format!("--file {} -c 2 10.0.0.1", file.path());
+        let cli_args = parse_line(&line).await.unwrap();
+        assert_eq!(cli_args.count, 2);
+        assert_eq!(names(&cli_args), vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+
+        let line = format!("-c 2 --file {} -q", file.path());
+        let cli_args = parse_line(&line).await.unwrap();
+        assert!(cli_args.quiet);
+        assert_eq!(names(&cli_args), vec!["10.0.0.2", "10.0.0.3"]);
+
+        let line = format!("10.0.0.1 --file {}", file.path());
+        assert_eq!(
+            parse_line(&line).await.unwrap_err(),
+            "Invalid argument: invalid option order"
+        );
+    }
+}
@@ -1,5 +1,8 @@
 mod cli;
+mod mtr;
 mod ping;
+mod pmtu;
+mod trace;
 
 use std::sync::Arc;
 
@@ -8,7 +11,11 @@ use signal_hook_tokio::Signals;
 
 use futures::stream::StreamExt;
 
-use ping::Ping;
+use cli::Mode;
+use mtr::Mtr;
+use ping::{Ping, Pings};
+use pmtu::Pmtu;
+use trace::Traceroute;
 
 #[tokio::main]
 async fn main() {
@@ -18,7 +25,32 @@ async fn main() {
         std::process::exit(1);
     }
 
-    let signals = Signals::new(&[SIGINT]);
+    let cli_args = cli_args.unwrap();
+    if cli_args.mode == Mode::Traceroute {
+        if let Err(err) = Traceroute::new(cli_args).run().await {
+            println!("traceroute error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if cli_args.mode == Mode::Capabilities {
+        if let Err(err) = ping::capabilities(&cli_args).await {
+            println!("probes error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if cli_args.mode == Mode::Pmtu {
+        if let Err(err) = Pmtu::new(cli_args).run().await {
+            println!("pmtu error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let signals = Signals::new([SIGINT]);
     if let Err(err) = signals {
         println!("create singals error: {}", err);
         std::process::exit(1);
@@ -27,22 +59,53 @@ async fn main() {
     let signals = signals.unwrap();
     let handle = signals.handle();
 
-    let ping = Arc::new(Ping::new(cli_args.unwrap()));
+    if cli_args.mode == Mode::Mtr {
+        let mtr = Arc::new(Mtr::new(cli_args));
+        let mtr_by_signal = mtr.clone();
+        tokio::spawn(async move { handle_signals(signals, || mtr_by_signal.finish()).await });
+
+        if let Err(err) = mtr.run().await {
+            println!("mtr error: {}", err);
+            std::process::exit(1);
+        }
+
+        handle.close();
+        return;
+    }
+
+    if cli_args.targets.len() > 1 {
+        let pings = Arc::new(Pings::new(cli_args));
+        let pings_by_signal = pings.clone();
+        tokio::spawn(
+            async move { handle_signals(signals, || pings_by_signal.print_summary()).await },
+        );
+
+        if let Err(err) = pings.run().await {
+            println!("ping error: {}", err);
+            std::process::exit(2);
+        }
+
+        handle.close();
+        std::process::exit(pings.exit_code());
+    }
+
+    let ping = Arc::new(Ping::new(cli_args));
     let ping_by_signal = ping.clone();
-    tokio::spawn(async move { handle_signals(signals, &ping_by_signal).await });
+    tokio::spawn(async move { handle_signals(signals, || ping_by_signal.print_stats()).await });
 
     if let Err(err) = ping.run().await {
         println!("ping error: {}", err);
-        std::process::exit(1);
+        std::process::exit(2);
     }
 
     handle.close();
+    std::process::exit(ping.exit_code());
 }
 
-async fn handle_signals(mut signals: Signals, ping: &Arc<Ping>) {
+async fn handle_signals<F: Fn()>(mut signals: Signals, on_exit: F) {
     while let Some(signal) = signals.next().await {
         if signal == SIGINT {
-            ping.print_stats();
+            on_exit();
             signals.handle().close();
             std::process::exit(0);
         }
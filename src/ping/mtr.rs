@@ -0,0 +1,121 @@
+use std::{error::Error, fmt::Write as _, io::Write, sync::Arc};
+use tokio::time::interval;
+
+use crate::cli::CliArgs;
+use crate::ping::Ping;
+
+#[derive(Debug)]
+pub struct Mtr {
+    args: CliArgs,
+    // a ping per ttl, hops behind the one the host answered from are
+    // stopped and left out
+    hops: Vec<Arc<Ping>>,
+}
+
+impl Mtr {
+    pub fn new(args: CliArgs) -> Self {
+        let hops = (1..=args.max_hops)
+            .map(|ttl| Arc::new(Ping::hop(args.clone(), ttl)))
+            .collect();
+        Mtr { args, hops }
+    }
+
+    ///
+    /// Pings every hop at once, each with the probes of the ping mode
+    /// limited to its ttl. The table is redrawn every interval until they
+    /// are all done.
+    ///
+    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
+        let runs = self.hops.iter().map(|hop| async move {
+            if let Err(err) = hop.run().await {
+                println!("mtr error: {}", err);
+            }
+        });
+        let pinging = futures::future::join_all(runs);
+        let drawing = async {
+            let mut ticker = interval(self.args.interval);
+            loop {
+                ticker.tick().await;
+                let last_ttl = self.last_ttl();
+                for hop in &self.hops[last_ttl..] {
+                    hop.stop();
+                }
+                if !self.args.quiet {
+                    print!("\x1b[H\x1b[2J{}", self.report());
+                    let _ = std::io::stdout().flush();
+                }
+            }
+        };
+        tokio::select! {
+            _ = pinging => {}
+            _ = drawing => {}
+        }
+
+        self.finish();
+
+        Ok(())
+    }
+
+    /// Hops up to the first one the probes got past.
+    fn last_ttl(&self) -> usize {
+        self.hops
+            .iter()
+            .position(|hop| hop.arrived())
+            .map_or(self.hops.len(), |index| index + 1)
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "mtr to {} ({})\n{:>4} {:<40} {:>6} {:>5} {:>7} {:>7} {:>7} {:>7} {:>7}",
+            self.args.host_name,
+            self.args.host_addr,
+            "",
+            "Host",
+            "Loss%",
+            "Snt",
+            "Last",
+            "Avg",
+            "Best",
+            "Wrst",
+            "StDev"
+        );
+
+        for (index, hop) in self.hops[..self.last_ttl()].iter().enumerate() {
+            let host = match hop.responder() {
+                Some(addr) => addr.to_string(),
+                None => "???".to_string(),
+            };
+            let stats = hop.stats();
+            let best = if stats.rx_count > 0 { stats.rtt_min } else { 0 };
+            let _ = writeln!(
+                report,
+                "{:>3}. {:<40} {:>5.1}% {:>5} {:>7.1} {:>7.1} {:>7.1} {:>7.1} {:>7.1}",
+                index + 1,
+                host,
+                stats.loss(),
+                stats.tx_count,
+                stats.rtt_last as f64 / 1000.0,
+                stats.avg() / 1000.0,
+                best as f64 / 1000.0,
+                stats.rtt_max as f64 / 1000.0,
+                stats.stddev() / 1000.0
+            );
+        }
+
+        report
+    }
+
+    /// Prints the final table, and writes it to the report file if one was asked for.
+    pub fn finish(&self) {
+        let report = self.report();
+        println!("\n{}", report);
+
+        if let Some(file) = &self.args.report {
+            if let Err(err) = std::fs::write(file, &report) {
+                println!("write report {} error: {}", file, err);
+            }
+        }
+    }
+}
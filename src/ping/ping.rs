@@ -1,27 +1,43 @@
 use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
     error::Error,
+    io::{self, Write},
     net::{IpAddr, SocketAddr},
-    sync::{Arc, Mutex},
-    time::Instant,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
     net::UdpSocket,
-    time::{sleep, timeout, Duration},
+    sync::Notify,
+    time::{sleep_until, timeout, Duration},
 };
 
-use buf_view::BufViewMut;
+use ping_proxy::proto::{
+    Limits, Probe, Reply, Request, Status, Timestamps, INVALID_SEND, MAX_MESSAGE,
+};
 
-use crate::cli::CliArgs;
+use crate::cli::{CliArgs, Pace, Until};
 
 #[derive(Debug)]
-struct Stats {
-    rtt_min: u32,
-    rtt_max: u32,
-    rtt_total: u64,
-    rx_count: u32,
-    tx_count: u32,
-    lost_count: u32,
-    timeout_count: u32,
+pub struct Stats {
+    pub rtt_min: u32,
+    pub rtt_max: u32,
+    pub rtt_last: u32,
+    pub rtt_total: u64,
+    pub rtt_sq_total: u64,
+    pub rx_count: u32,
+    pub tx_count: u32,
+    pub lost_count: u32,
+    pub timeout_count: u32,
+    // replies to a probe that was already answered
+    pub dup_count: u32,
+    // replies that came after their probe timed out
+    pub late_count: u32,
+    // ICMP errors instead of a reply, unreachable or time exceeded
+    pub error_count: u32,
 }
 
 impl Stats {
@@ -29,28 +45,85 @@ impl Stats {
         Stats {
             rtt_min: u32::MAX,
             rtt_max: 0,
+            rtt_last: 0,
             rtt_total: 0,
+            rtt_sq_total: 0,
             rx_count: 0,
             tx_count: 0,
             lost_count: 0,
             timeout_count: 0,
+            dup_count: 0,
+            late_count: 0,
+            error_count: 0,
+        }
+    }
+
+    pub fn update(&mut self, elapse: u32) {
+        if elapse != u32::MAX {
+            if self.rtt_min > elapse {
+                self.rtt_min = elapse;
+            }
+
+            if self.rtt_max < elapse {
+                self.rtt_max = elapse;
+            }
+
+            self.rtt_last = elapse;
+            self.rtt_total += elapse as u64;
+            self.rtt_sq_total += elapse as u64 * elapse as u64;
+        }
+        self.rx_count += 1;
+    }
+
+    /// Loss of the probes the proxy sent out, the ones it refused or that
+    /// never reached it do not count.
+    pub fn loss(&self) -> f32 {
+        let sent = self.tx_count.saturating_sub(self.lost_count);
+        if sent == 0 {
+            return 0.0;
+        }
+        sent.saturating_sub(self.rx_count) as f32 * 100.0 / sent as f32
+    }
+
+    /// Average rtt in micro seconds.
+    pub fn avg(&self) -> f64 {
+        if self.rx_count == 0 {
+            return 0.0;
         }
+        self.rtt_total as f64 / self.rx_count as f64
+    }
+
+    /// Standard deviation of rtt in micro seconds.
+    pub fn stddev(&self) -> f64 {
+        if self.rx_count == 0 {
+            return 0.0;
+        }
+        let avg = self.avg();
+        let variance = self.rtt_sq_total as f64 / self.rx_count as f64 - avg * avg;
+        variance.max(0.0).sqrt()
     }
 }
 
 impl std::fmt::Display for Stats {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let loss = if self.tx_count > 0 {
-            (self.tx_count - self.rx_count) * 100 / self.tx_count
-        } else {
-            0
-        };
-
         let _ = write!(
             f,
-            "{} packets tx, {} rx, {} lost, {} timeout, {}% packets loss",
-            self.tx_count, self.rx_count, self.lost_count, self.timeout_count, loss
+            "{} packets tx, {} rx, {} lost, {} timeout, {:.1}% packets loss",
+            self.tx_count,
+            self.rx_count,
+            self.lost_count,
+            self.timeout_count,
+            self.loss()
         );
+        if self.dup_count > 0 {
+            let _ = write!(f, ", +{} duplicates", self.dup_count);
+        }
+        if self.late_count > 0 {
+            let _ = write!(f, ", {} late", self.late_count);
+        }
+        if self.error_count > 0 {
+            let _ = write!(f, ", {} errors", self.error_count);
+        }
 
         if self.rx_count > 0 {
             let _ = write!(
@@ -65,10 +138,160 @@ impl std::fmt::Display for Stats {
     }
 }
 
+/// The sequences answered among the last 64k sent, a reply to a marked
+/// one is a duplicate.
+#[derive(Debug)]
+struct Received {
+    bits: Vec<u64>,
+}
+
+impl Received {
+    const SIZE: u32 = 1 << 16;
+
+    fn new() -> Self {
+        Received {
+            bits: vec![0; (Self::SIZE / 64) as usize],
+        }
+    }
+
+    fn sent(&mut self, seq: u32) {
+        let bit = seq % Self::SIZE;
+        self.bits[(bit / 64) as usize] &= !(1 << (bit % 64));
+    }
+
+    /// Marks `seq` answered, true when it already was.
+    fn mark(&mut self, seq: u32) -> bool {
+        let bit = seq % Self::SIZE;
+        let word = &mut self.bits[(bit / 64) as usize];
+        let seen = *word & (1 << (bit % 64)) != 0;
+        *word |= 1 << (bit % 64);
+        seen
+    }
+}
+
+/// Probes sent and not yet answered or timed out.
+const MAX_IN_FLIGHT: usize = 1024;
+/// Longest a flood waits for a reply before the next probe.
+const FLOOD_GAP: Duration = Duration::from_millis(10);
+/// Shortest adaptive interval.
+const ADAPTIVE_GAP: Duration = Duration::from_millis(200);
+/// How long a probe refused over the proxy's rate waits to go again, it
+/// doubles with every refusal.
+const RATE_BACKOFF: Duration = Duration::from_millis(100);
+/// Refusals over the rate before a probe is given up.
+const MAX_RETRIES: u32 = 5;
+/// Longest wait for the proxy's limits, an older proxy does not answer.
+const LIMITS_WAIT: Duration = Duration::from_millis(500);
+
+/// How far apart probes go, at least `min_gap` and at most `max_gap`,
+/// in between as soon as no probe is in flight.
+#[derive(Debug)]
+struct Pacing {
+    min_gap: Duration,
+    max_gap: Duration,
+    window: usize,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    deadline: Instant,
+    // the first responder's rtt and everyone that answered, for broadcast
+    // and multicast probes
+    first: Option<u32>,
+    responders: HashSet<IpAddr>,
+    // when a probe refused over the proxy's rate goes again, and how often
+    // it was
+    retry: Option<Instant>,
+    retries: u32,
+}
+
+impl InFlight {
+    fn new(deadline: Instant) -> Self {
+        InFlight {
+            deadline,
+            first: None,
+            responders: HashSet::new(),
+            retry: None,
+            retries: 0,
+        }
+    }
+}
+
+/// Exponentially distributed gaps from xorshift64, the same seed draws
+/// the same gaps.
+#[derive(Debug)]
+struct Gaps {
+    state: u64,
+}
+
+impl Gaps {
+    fn new(seed: u64) -> Self {
+        // a splitmix64 step spreads small seeds over the bits, xorshift
+        // would start out with tiny numbers and long gaps
+        let mut state = seed.wrapping_add(0x9E3779B97F4A7C15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94D049BB133111EB);
+        state ^= state >> 31;
+        // zero is the only fixed point of xorshift
+        Gaps {
+            state: state.max(1),
+        }
+    }
+
+    fn next(&mut self, mean: Duration) -> Duration {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        // uniform in (0, 1], its log is finite
+        let uniform = ((self.state >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        mean.mul_f64(-uniform.ln())
+    }
+}
+
+/// The first instant from `after` on that is a multiple of `gap` on the
+/// wall clock, so probes of clients with synced clocks line up.
+fn aligned(after: Instant, gap: Duration) -> Instant {
+    let now = Instant::now();
+    let after = after.max(now);
+    let wall = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        + after.duration_since(now);
+    let gap = gap.as_nanos().max(1);
+    let wait = (gap - wall.as_nanos() % gap) % gap;
+    after + Duration::from_nanos(wait as u64)
+}
+
 #[derive(Debug)]
 pub struct Ping {
     args: CliArgs,
     stats: Arc<Mutex<Stats>>,
+    // clock offsets of the host in ms, from timestamp replies
+    offsets: Mutex<Vec<f64>>,
+    // everyone that answered a broadcast or multicast probe
+    responders: Mutex<BTreeSet<IpAddr>>,
+    received: Mutex<Received>,
+    inflight: Mutex<BTreeMap<u32, InFlight>>,
+    // cleared once the last probe is sent
+    sending: AtomicBool,
+    // a probe went out, or the last one did
+    sent: Notify,
+    // a probe left the flight
+    freed: Notify,
+    // answered or not, and how many probes in a row
+    streak: Mutex<(bool, u32)>,
+    // the until state is reached
+    reached: AtomicBool,
+    // the proxy refused a probe for good
+    refused: AtomicBool,
+    stop: Notify,
+    // no probe goes out before, the proxy refused one over its rate
+    hold: Mutex<Instant>,
+    // the ttl of the probes when this is a hop of mtr, the last router or
+    // host that answered and whether it was the end of the path
+    hop: Option<u8>,
+    responder: Mutex<Option<IpAddr>>,
+    arrived: AtomicBool,
 }
 
 impl Ping {
@@ -76,24 +299,217 @@ impl Ping {
         Ping {
             args,
             stats: Arc::new(Mutex::new(Stats::new())),
+            offsets: Mutex::new(Vec::new()),
+            responders: Mutex::new(BTreeSet::new()),
+            received: Mutex::new(Received::new()),
+            inflight: Mutex::new(BTreeMap::new()),
+            sending: AtomicBool::new(true),
+            sent: Notify::new(),
+            freed: Notify::new(),
+            streak: Mutex::new((false, 0)),
+            reached: AtomicBool::new(false),
+            refused: AtomicBool::new(false),
+            stop: Notify::new(),
+            hold: Mutex::new(Instant::now()),
+            hop: None,
+            responder: Mutex::new(None),
+            arrived: AtomicBool::new(false),
         }
     }
 
+    /// Probes hop `ttl` on the way to the host for mtr, quietly.
+    pub fn hop(mut args: CliArgs, ttl: u8) -> Self {
+        args.quiet = true;
+        let mut ping = Ping::new(args);
+        ping.hop = Some(ttl);
+        ping
+    }
+
     pub async fn run(&self) -> Result<(), Box<dyn Error>> {
-        println!(
-            "ping {} ({}) {} bytes of data",
-            self.args.host_name, self.args.host_addr, self.args.length
-        );
+        if !self.alone() {
+            // the summary table or mtr tells what was pinged
+        } else if self.args.probe == Probe::Tcp {
+            println!(
+                "ping {} ({}) tcp port {}",
+                self.args.host_name, self.args.host_addr, self.args.dest_port
+            );
+        } else if self.args.probe == Probe::Timestamp {
+            println!(
+                "ping {} ({}) icmp timestamp",
+                self.args.host_name, self.args.host_addr
+            );
+        } else if self.args.probe == Probe::Coap {
+            println!(
+                "ping {} ({}) coap port {}",
+                self.args.host_name, self.args.host_addr, self.args.dest_port
+            );
+        } else if self.args.probe == Probe::Udp {
+            println!(
+                "ping {} ({}) udp port {}, {} bytes of data",
+                self.args.host_name, self.args.host_addr, self.args.dest_port, self.args.length
+            );
+        } else {
+            println!(
+                "ping {} ({}) {}({}) bytes of data",
+                self.args.host_name,
+                self.args.host_addr,
+                self.args.length,
+                self.args.length as u32 + self.args.header_len() as u32
+            );
+        }
+        let start = Instant::now();
         let socket = UdpSocket::bind("0.0.0.0:0").await?;
         let proxy_addr = SocketAddr::new(self.args.proxy, self.args.port);
         socket.connect(&proxy_addr).await?;
 
-        let mut buf = [0u8; 64];
-        let mut buf = BufViewMut::wrap(&mut buf);
+        // a flood or a short interval must not run into the proxy's limits
+        let fast = self.args.interval < Duration::from_secs(1);
+        let limits = if self.args.pace != Pace::Interval || fast {
+            self.limits(&socket).await
+        } else {
+            None
+        };
+        let pacing = self.pacing(limits);
+        if self.args.pace == Pace::Poisson {
+            println!(
+                "{}poisson gaps averaging {} ms, seed {}",
+                self.prefix(),
+                self.args.interval.as_millis(),
+                self.args.seed
+            );
+        }
+
+        // probes go out on schedule while replies and timeouts are handled
+        // on the side, until the count is done, the deadline passes or the
+        // until state is reached
+        let probing = async {
+            tokio::join!(
+                self.send_probes(&socket, &pacing),
+                self.recv_replies(&socket)
+            )
+        };
+        let deadline = async {
+            match self.args.deadline {
+                Some(deadline) => sleep_until((start + deadline).into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = probing => {}
+            _ = deadline => {}
+            _ = self.stop.notified() => {}
+        }
+        if let (Some(until), false) = (self.args.until, self.refused.load(Ordering::SeqCst)) {
+            let state = if until == Until::Up { "up" } else { "down" };
+            if self.reached.load(Ordering::SeqCst) {
+                println!("{} is {}", self.args.host_name, state);
+            } else {
+                println!("{} is not {}", self.args.host_name, state);
+            }
+        }
+
+        if self.alone() {
+            self.print_stats();
+        }
+
+        Ok(())
+    }
+
+    /// A run of its own, not one of several targets or a hop of mtr.
+    fn alone(&self) -> bool {
+        !self.args.many && self.hop.is_none()
+    }
+
+    pub fn stop(&self) {
+        self.stop.notify_one();
+    }
+
+    pub fn stats(&self) -> MutexGuard<'_, Stats> {
+        self.stats.lock().unwrap()
+    }
+
+    /// The last router or host that answered a hop probe.
+    pub fn responder(&self) -> Option<IpAddr> {
+        *self.responder.lock().unwrap()
+    }
+
+    /// A hop probe got further than the hop, the host or a router that
+    /// cannot pass it on answered.
+    pub fn arrived(&self) -> bool {
+        self.arrived.load(Ordering::SeqCst)
+    }
+
+    /// Lines of one of several targets start with its name.
+    fn prefix(&self) -> String {
+        if self.args.many {
+            format!("{}: ", self.args.host_name)
+        } else {
+            String::new()
+        }
+    }
+
+    /// What the proxy takes from a client, `None` when it does not say.
+    async fn limits(&self, socket: &UdpSocket) -> Option<Limits> {
+        let mut request = Request::new(0, 0, self.args.host_addr);
+        request.probe = Probe::Capabilities;
+        let mut buf = [0u8; MAX_MESSAGE];
+        let len = request.encode(&mut buf);
+        socket.send(&buf[..len]).await.ok()?;
+
+        let wait = Duration::from_millis(self.args.timeout.into()).min(LIMITS_WAIT);
+        let reply = wait_reply(socket, &mut buf, 0, wait).await.ok()??;
+        reply.limits
+    }
+
+    fn pacing(&self, limits: Option<Limits>) -> Pacing {
+        let interval = self.args.interval;
+        let (min_gap, max_gap) = match self.args.pace {
+            Pace::Flood if interval.is_zero() => (Duration::ZERO, FLOOD_GAP),
+            Pace::Adaptive => (ADAPTIVE_GAP.min(interval), interval),
+            // the gaps are drawn, only the proxy's limit holds them apart
+            Pace::Poisson => (Duration::ZERO, Duration::ZERO),
+            Pace::Interval | Pace::Flood | Pace::Aligned => (interval, interval),
+        };
+        let mut pacing = Pacing {
+            min_gap,
+            max_gap,
+            window: MAX_IN_FLIGHT,
+        };
+        if let Some(limits) = limits {
+            let gap = Duration::from_secs(1) / limits.rate.max(1);
+            if gap > interval && !interval.is_zero() {
+                println!(
+                    "{}interval held to {} ms, the proxy takes {} probes a second",
+                    self.prefix(),
+                    gap.as_millis(),
+                    limits.rate
+                );
+            }
+            pacing.min_gap = pacing.min_gap.max(gap);
+            pacing.max_gap = pacing.max_gap.max(pacing.min_gap);
+            pacing.window = pacing.window.min(limits.in_flight as usize);
+        }
+        pacing
+    }
+
+    /// When probe `seq` + 1 goes out, the one before went at `last_time`.
+    fn send_at(&self, seq: u32, last_time: Instant, pacing: &Pacing, gaps: &mut Gaps) -> Instant {
+        match self.args.pace {
+            Pace::Aligned if seq == 0 => aligned(Instant::now(), pacing.min_gap),
+            _ if seq == 0 => Instant::now(),
+            Pace::Poisson => last_time + gaps.next(self.args.interval).max(pacing.min_gap),
+            // half a gap on, a late timer does not skip a multiple
+            Pace::Aligned => aligned(last_time + pacing.min_gap / 2, pacing.min_gap),
+            _ => last_time + pacing.min_gap,
+        }
+    }
+
+    async fn send_probes(&self, socket: &UdpSocket, pacing: &Pacing) {
+        let mut buf = [0u8; MAX_MESSAGE];
         let mut count = self.args.count;
         let mut seq = 0;
         let mut last_time = Instant::now();
-        let interval = self.args.interval as u32 * 1000;
+        let mut gaps = Gaps::new(self.args.seed);
 
         loop {
             if self.args.count != 0 {
@@ -103,116 +519,644 @@ impl Ping {
                 count -= 1;
             }
 
+            // flood and adaptive go early once every probe is in
+            let at = self.send_at(seq, last_time, pacing, &mut gaps);
+            let at = at.max(*self.hold.lock().unwrap());
+            sleep_until(at.into()).await;
             if seq != 0 {
-                let elapse = Instant::now().duration_since(last_time).as_millis() as u32;
-                if elapse < interval {
-                    let delay = interval - elapse;
-                    sleep(Duration::from_millis(delay as u64)).await;
+                let latest = at.max(last_time + pacing.max_gap);
+                loop {
+                    let freed = self.freed.notified();
+                    if Instant::now() >= latest || self.inflight.lock().unwrap().is_empty() {
+                        break;
+                    }
+                    tokio::select! {
+                        _ = freed => {}
+                        _ = sleep_until(latest.into()) => {}
+                    }
                 }
             }
 
+            // a slot frees when a probe is answered or times out
+            loop {
+                let freed = self.freed.notified();
+                if self.inflight.lock().unwrap().len() < pacing.window {
+                    break;
+                }
+                freed.await;
+            }
+
             seq += 1;
             {
                 let mut stats = self.stats.lock().unwrap();
                 stats.tx_count = seq;
             }
+            self.received.lock().unwrap().sent(seq);
 
-            build_request(&mut buf, seq, self.args.length, &self.args.host_addr);
+            let len = self.request(seq).encode(&mut buf);
 
             last_time = Instant::now();
+            let deadline = last_time + Duration::from_millis(self.args.timeout.into());
+            self.inflight
+                .lock()
+                .unwrap()
+                .insert(seq, InFlight::new(deadline));
+            self.sent.notify_waiters();
 
-            if let Err(err) = socket.send(buf.as_slice()).await {
-                let mut stats = self.stats.lock().unwrap();
-                stats.lost_count += 1;
-                if !self.args.quiet {
-                    println!(
-                        "{} packets tx {} timeout {} lost",
-                        stats.tx_count, stats.timeout_count, stats.lost_count
-                    );
-                }
+            if let Err(err) = socket.send(&buf[..len]).await {
+                self.send_failed(seq, &err.to_string());
+            } else if self.args.pace == Pace::Flood && !self.args.quiet {
+                print!(".");
+                let _ = io::stdout().flush();
+            }
+        }
+
+        self.sending.store(false, Ordering::SeqCst);
+        self.sent.notify_waiters();
+    }
+
+    fn request(&self, seq: u32) -> Request {
+        let mut request = Request::new(seq, self.args.length, self.args.host_addr);
+        request.probe = self.args.probe;
+        request.port = self.args.dest_port;
+        request.iface = self.args.iface.clone();
+        request.unicast = self.args.unicast;
+        request.timeout = self.args.timeout;
+        request.tos = self.args.tos;
+        request.payload = self.args.payload.clone();
+        request.vlan = self.args.vlan;
+        request.netns = self.args.netns.clone();
+        request.multi = self.args.multi;
+        request.ttl = self.hop;
+        request
+    }
+
+    /// A probe that never reached the proxy is lost, it says nothing about
+    /// the host.
+    fn send_failed(&self, seq: u32, error: &str) {
+        self.inflight.lock().unwrap().remove(&seq);
+        self.freed.notify_waiters();
+        self.stats.lock().unwrap().lost_count += 1;
+        if !self.args.quiet {
+            println!(
+                "{}seq {} send to proxy error: {}",
+                self.prefix(),
+                seq,
+                error
+            );
+        }
+    }
+
+    /// Sends the probes refused over the proxy's rate whose wait is over
+    /// again.
+    async fn resend(&self, socket: &UdpSocket) {
+        let now = Instant::now();
+        let wait = Duration::from_millis(self.args.timeout.into());
+        let due: Vec<u32> = {
+            let mut inflight = self.inflight.lock().unwrap();
+            inflight
+                .iter_mut()
+                .filter(|(_, probe)| probe.retry.is_some_and(|retry| retry <= now))
+                .map(|(seq, probe)| {
+                    probe.retry = None;
+                    probe.deadline = now + wait;
+                    *seq
+                })
+                .collect()
+        };
+
+        let mut buf = [0u8; MAX_MESSAGE];
+        for seq in due {
+            let len = self.request(seq).encode(&mut buf);
+            if let Err(err) = socket.send(&buf[..len]).await {
+                self.send_failed(seq, &err.to_string());
+            }
+        }
+    }
+
+    ///
+    /// Takes replies until the last probe is sent and none is in flight, a
+    /// probe whose deadline passes times out.
+    ///
+    async fn recv_replies(&self, socket: &UdpSocket) {
+        let mut buf = [0u8; MAX_MESSAGE];
+
+        loop {
+            let sent = self.sent.notified();
+            self.resend(socket).await;
+            let next = self.expire(Instant::now());
+            if next.is_none() && !self.sending.load(Ordering::SeqCst) {
+                break;
+            }
+            // nothing in flight, wait for the next probe
+            let wait = next.unwrap_or_else(|| Instant::now() + Duration::from_secs(3600));
+
+            let len = tokio::select! {
+                result = socket.recv(&mut buf) => match result {
+                    Ok(len) => len,
+                    Err(err) => {
+                        if self.args.show_error {
+                            println!("read error: {}", err)
+                        }
+                        continue;
+                    }
+                },
+                _ = sleep_until(wait.into()) => continue,
+                _ = sent => continue,
+            };
+
+            if let Some(reply) = self.decode(&buf[..len]) {
+                self.take_reply(&reply);
+            }
+        }
+    }
+
+    fn decode(&self, buf: &[u8]) -> Option<Reply> {
+        match Reply::decode(buf) {
+            Ok(reply) => Some(reply),
+            Err(err) => {
                 if self.args.show_error {
-                    println!("send to proxy error: {}", err)
+                    println!("invalid reply: {}", err);
                 }
-                continue;
+                None
+            }
+        }
+    }
+
+    ///
+    /// Matches a reply to its probe by seq. Every responder to a broadcast
+    /// or multicast probe answers on its own, they are taken until the
+    /// timeout.
+    ///
+    fn take_reply(&self, reply: &Reply) {
+        let mut inflight = self.inflight.lock().unwrap();
+        let probe = match inflight.get_mut(&reply.seq) {
+            Some(probe) => probe,
+            None => {
+                drop(inflight);
+                self.stray_reply(reply);
+                return;
             }
+        };
 
-            let rx = socket.recv(buf.as_raw_slice());
-            let result = timeout(Duration::from_millis(self.args.timeout.into()), rx).await;
-            if let Err(err) = result {
-                let mut stats = self.stats.lock().unwrap();
-                stats.timeout_count += 1;
-                if !self.args.quiet {
+        // the proxy's word, not the host's
+        if reply.status == Status::Invalid {
+            if reply.code == INVALID_SEND {
+                inflight.remove(&reply.seq);
+                drop(inflight);
+                self.freed.notify_waiters();
+                self.proxy_failed(reply);
+                return;
+            }
+            if reply.limits.is_some() && probe.retries < MAX_RETRIES {
+                let at = Instant::now() + RATE_BACKOFF * (1 << probe.retries);
+                probe.retries += 1;
+                probe.retry = Some(at);
+                probe.deadline = at + Duration::from_millis(self.args.timeout.into());
+                *self.hold.lock().unwrap() = at;
+                drop(inflight);
+                if self.args.show_error {
                     println!(
-                        "{} packets tx {} timeout {} lost",
-                        stats.tx_count, stats.timeout_count, stats.lost_count
+                        "{}seq {} over the proxy's rate, again in {} ms",
+                        self.prefix(),
+                        reply.seq,
+                        (at - Instant::now()).as_millis()
                     );
                 }
-                if self.args.show_error {
-                    println!("recv from proxy error: {}", err)
-                }
-                continue;
+                return;
             }
+            inflight.remove(&reply.seq);
+            drop(inflight);
+            self.freed.notify_waiters();
+            self.refuse(reply);
+            return;
+        }
 
-            let result = result.unwrap();
-            if let Err(err) = result {
-                let mut stats = self.stats.lock().unwrap();
-                stats.lost_count += 1;
-                if !self.args.quiet {
-                    println!(
-                        "{} packets tx {} timeout {} lost",
-                        stats.tx_count, stats.timeout_count, stats.lost_count
-                    );
+        if !self.args.multi {
+            inflight.remove(&reply.seq);
+            drop(inflight);
+            self.freed.notify_waiters();
+            self.received.lock().unwrap().mark(reply.seq);
+            match self.show_reply(reply) {
+                Some(elapse) => {
+                    self.update_stats(elapse);
+                    self.unflood();
+                    self.outcome(true);
                 }
+                None => self.outcome(false),
+            }
+            return;
+        }
 
-                if self.args.show_error {
-                    println!("read error: {}", err)
+        let from = reply.from.unwrap_or(self.args.host_addr);
+        if !probe.responders.insert(from) {
+            drop(inflight);
+            self.count_stray(reply, true);
+            return;
+        }
+        if let Some(elapse) = self.show_reply(reply) {
+            if probe.first.is_none() {
+                self.unflood();
+            }
+            probe.first.get_or_insert(elapse);
+            self.received.lock().unwrap().mark(reply.seq);
+        }
+    }
+
+    /// A probe the proxy took but failed to send is lost like one that never
+    /// reached it, the next probe may well go out.
+    fn proxy_failed(&self, reply: &Reply) {
+        self.stats.lock().unwrap().lost_count += 1;
+        if !self.args.quiet {
+            println!(
+                "{}seq {} proxy send error: {}",
+                self.prefix(),
+                reply.seq,
+                reply.error.as_deref().unwrap_or_default()
+            );
+        }
+    }
+
+    ///
+    /// A probe the proxy will not carry out, or one still refused over the
+    /// rate after every retry. A refusal other than the rate's would meet
+    /// every probe, the run stops.
+    ///
+    fn refuse(&self, reply: &Reply) {
+        self.stats.lock().unwrap().lost_count += 1;
+        println!(
+            "{}seq {} rejected by proxy: {}",
+            self.prefix(),
+            reply.seq,
+            reply.error.as_deref().unwrap_or_default()
+        );
+        if !reply.probes.is_empty() {
+            println!("proxy supports {}", probe_names(&reply.probes));
+        }
+        if reply.limits.is_none() {
+            self.refused.store(true, Ordering::SeqCst);
+            self.stop.notify_one();
+        }
+    }
+
+    ///
+    /// Times out the probes whose deadline is before `now`, a broadcast or
+    /// multicast probe counts as received once, with the time of the first
+    /// responder. Returns the next deadline in flight.
+    ///
+    fn expire(&self, now: Instant) -> Option<Instant> {
+        let mut inflight = self.inflight.lock().unwrap();
+        let expired: Vec<u32> = inflight
+            .iter()
+            .filter(|(_, probe)| probe.deadline <= now)
+            .map(|(seq, _)| *seq)
+            .collect();
+        for seq in &expired {
+            let probe = inflight.remove(seq).unwrap();
+            match probe.first {
+                Some(elapse) => {
+                    if self.lines() {
+                        println!(
+                            "{}seq {}: {} responders",
+                            self.prefix(),
+                            seq,
+                            probe.responders.len()
+                        );
+                    }
+                    self.update_stats(elapse);
+                    self.responders.lock().unwrap().extend(probe.responders);
+                    self.outcome(true);
+                }
+                None => {
+                    self.timed_out(*seq);
+                    self.outcome(false);
                 }
-                continue;
             }
+        }
+        if !expired.is_empty() {
+            self.freed.notify_waiters();
+        }
+
+        inflight
+            .values()
+            .map(|probe| probe.retry.unwrap_or(probe.deadline))
+            .min()
+    }
 
-            let len = result.unwrap();
-            self.process_reply(&mut buf, len);
+    ///
+    /// Counts the probes answered or not in a row, the run stops once
+    /// `hysteresis` of them make the state `--until-up` or `--until-down`
+    /// waits for.
+    ///
+    fn outcome(&self, up: bool) {
+        let until = match self.args.until {
+            Some(until) => until,
+            None => return,
+        };
+        let mut streak = self.streak.lock().unwrap();
+        if streak.0 == up {
+            streak.1 += 1;
+        } else {
+            *streak = (up, 1);
+        }
+        if up == (until == Until::Up) && streak.1 >= self.args.hysteresis {
+            self.reached.store(true, Ordering::SeqCst);
+            self.stop.notify_one();
         }
+    }
 
-        self.print_stats();
+    /// 0 once the until state is reached, or without one when any probe
+    /// was answered, 2 when the proxy refused the probes, 1 otherwise.
+    pub fn exit_code(&self) -> i32 {
+        if self.refused.load(Ordering::SeqCst) {
+            return 2;
+        }
+        let done = match self.args.until {
+            Some(_) => self.reached.load(Ordering::SeqCst),
+            None => self.stats.lock().unwrap().rx_count > 0,
+        };
+        if done {
+            0
+        } else {
+            1
+        }
+    }
 
-        Ok(())
+    /// A line per reply and timeout, a flood only shows dots.
+    fn lines(&self) -> bool {
+        !self.args.quiet && self.args.pace != Pace::Flood
     }
 
-    fn process_reply(&self, buf: &mut BufViewMut, len: usize) {
-        buf.clear();
-        buf.set_writer_index(len);
-        let seq = buf.read_u32();
-        let elapse = buf.read_u32();
-        let ttl = buf.read_u8();
+    /// Takes back the dot of a flood probe that was answered.
+    fn unflood(&self) {
+        if self.args.pace == Pace::Flood && !self.args.quiet {
+            print!("\x08 \x08");
+            let _ = io::stdout().flush();
+        }
+    }
 
-        println!(
-            "{} bytes from {}: seq {} ttl {} time {}.{:03} ms",
-            self.args.length,
-            self.args.host_addr,
-            seq,
-            ttl,
-            elapse / 1000,
-            elapse % 1000
-        );
+    fn timed_out(&self, seq: u32) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.timeout_count += 1;
+        if !self.lines() {
+            return;
+        }
+        if self.args.probe == Probe::Tcp {
+            // nothing came back, not even a RST
+            println!(
+                "{}port {} filtered: seq {}",
+                self.prefix(),
+                self.args.dest_port,
+                seq
+            );
+        } else if matches!(self.args.probe, Probe::Udp | Probe::Coap) {
+            // open and silent or filtered, UDP cannot tell
+            println!(
+                "{}port {} no answer: seq {}",
+                self.prefix(),
+                self.args.dest_port,
+                seq
+            );
+        } else {
+            println!(
+                "{}{} packets tx {} timeout {} lost",
+                self.prefix(),
+                stats.tx_count,
+                stats.timeout_count,
+                stats.lost_count
+            );
+        }
+    }
 
-        self.update_stats(elapse);
+    ///
+    /// A reply to a probe no longer in flight. It is a duplicate when that
+    /// probe was answered already, late when it timed out, and never counts
+    /// for another probe.
+    ///
+    fn stray_reply(&self, reply: &Reply) {
+        let sent = self.stats.lock().unwrap().tx_count;
+        if reply.seq == 0 || reply.seq > sent {
+            if self.args.show_error {
+                println!("reply to unknown seq {}", reply.seq);
+            }
+            return;
+        }
+        if reply.status == Status::Invalid {
+            return;
+        }
+
+        // the proxy tells a duplicate of a reply this side never saw
+        let dup = self.received.lock().unwrap().mark(reply.seq) || reply.duplicate;
+        self.count_stray(reply, dup);
     }
 
-    fn update_stats(&self, elapse: u32) {
+    fn count_stray(&self, reply: &Reply, dup: bool) {
         let mut stats = self.stats.lock().unwrap();
-        if elapse != u32::MAX {
-            if stats.rtt_min > elapse {
-                stats.rtt_min = elapse;
+        if dup {
+            stats.dup_count += 1;
+        } else {
+            stats.late_count += 1;
+        }
+        if self.lines() {
+            println!(
+                "{}{}reply from {}: seq {} time {}.{:03} ms{}",
+                self.prefix(),
+                if dup { "" } else { "late " },
+                reply.from.unwrap_or(self.args.host_addr),
+                reply.seq,
+                reply.elapse / 1000,
+                reply.elapse % 1000,
+                if dup { " (DUP!)" } else { "" }
+            );
+        }
+    }
+
+    /// Prints a reply, the rtt comes back when it counts as received.
+    fn show_reply(&self, reply: &Reply) -> Option<u32> {
+        // the router's time exceeded answers a hop probe as much as the
+        // host's reply
+        if self.hop.is_some() {
+            if reply.from.is_some() {
+                *self.responder.lock().unwrap() = reply.from;
             }
+            if reply.status != Status::TimeExceeded {
+                self.arrived.store(true, Ordering::SeqCst);
+            }
+            return Some(reply.elapse);
+        }
 
-            if stats.rtt_max < elapse {
-                stats.rtt_max = elapse;
+        if matches!(self.args.probe, Probe::Tcp | Probe::Udp | Probe::Coap) {
+            return self.port_reply(reply);
+        }
+
+        if reply.status != Status::Reply {
+            self.error_reply(reply);
+            return None;
+        }
+
+        if let Some(stamps) = reply.timestamps {
+            self.timestamp_reply(reply, &stamps);
+            return Some(reply.elapse);
+        }
+
+        if self.args.pace == Pace::Flood {
+            return Some(reply.elapse);
+        }
+
+        if reply.mac.is_some() || reply.nd_flags.is_some() {
+            let mac = match reply.mac {
+                Some(mac) => mac.to_string(),
+                None => "no link layer address".to_string(),
+            };
+            print!(
+                "{}reply from {} [{}]: seq {} time {}.{:03} ms",
+                self.prefix(),
+                reply.from.unwrap_or(self.args.host_addr),
+                mac,
+                reply.seq,
+                reply.elapse / 1000,
+                reply.elapse % 1000
+            );
+            if let Some(flags) = reply.nd_flags {
+                for (bit, name) in [(0x80, "router"), (0x40, "solicited"), (0x20, "override")] {
+                    if flags & bit != 0 {
+                        print!(" {}", name);
+                    }
+                }
             }
+            println!();
+            return Some(reply.elapse);
+        }
+
+        print!(
+            "{}{} bytes from {}: seq {} ttl {} time {}.{:03} ms",
+            self.prefix(),
+            self.args.length as u32 + 8,
+            reply.from.unwrap_or(self.args.host_addr),
+            reply.seq,
+            reply.ttl,
+            reply.elapse / 1000,
+            reply.elapse % 1000
+        );
+        if let (Some(_), Some(tos)) = (self.args.tos, reply.tos) {
+            print!(" tos 0x{:02x}", tos);
+        }
+        if self.args.show_clock {
+            print!(" clock {}", reply.clock);
+        }
+        println!();
 
-            stats.rtt_total += elapse as u64;
+        Some(reply.elapse)
+    }
+
+    /// An ICMP error in place of the reply, from a router or the host
+    /// itself, the probe did not get through and is not received.
+    fn error_reply(&self, reply: &Reply) {
+        self.stats.lock().unwrap().error_count += 1;
+        if !self.lines() {
+            return;
+        }
+
+        let error = match reply.status {
+            Status::TimeExceeded => "time exceeded".to_string(),
+            Status::TooBig => match reply.mtu {
+                Some(mtu) => format!("frag needed, mtu {}", mtu),
+                None => "frag needed".to_string(),
+            },
+            _ => format!("destination unreachable, code {}", reply.code),
+        };
+        println!(
+            "{}from {}: seq {} {}",
+            self.prefix(),
+            reply.from.unwrap_or(self.args.host_addr),
+            reply.seq,
+            error
+        );
+    }
+
+    /// Shows the host's clock against the proxy's, hosts without a UTC
+    /// clock only tell how long they held the request.
+    fn timestamp_reply(&self, reply: &Reply, stamps: &Timestamps) {
+        if stamps.standard() {
+            self.offsets.lock().unwrap().push(stamps.offset());
+        }
+        if self.args.pace == Pace::Flood {
+            return;
+        }
+
+        print!(
+            "{}timestamp from {}: seq {} time {}.{:03} ms",
+            self.prefix(),
+            reply.from.unwrap_or(self.args.host_addr),
+            reply.seq,
+            reply.elapse / 1000,
+            reply.elapse % 1000
+        );
+        if stamps.standard() {
+            let offset = stamps.offset();
+            println!(
+                " offset {:+.1} ms forward {} ms back {} ms",
+                offset,
+                stamps.forward(),
+                stamps.back()
+            );
+        } else {
+            println!(
+                " non-standard clock, held {} ms",
+                stamps.transmit.wrapping_sub(stamps.receive) & 0x7FFF_FFFF
+            );
         }
-        stats.rx_count += 1;
+    }
+
+    /// A TCP or UDP probe reports the state of the port, a RST or a port
+    /// unreachable proves the host up as much as an answer and counts as
+    /// received.
+    fn port_reply(&self, reply: &Reply) -> Option<u32> {
+        let from = reply.from.unwrap_or(self.args.host_addr);
+        let port_unreachable = if self.args.host_addr.is_ipv4() { 3 } else { 4 };
+        let state = match (self.args.probe, reply.status) {
+            (Probe::Tcp, Status::Reply) => "open",
+            (Probe::Tcp, Status::Refused) => "closed",
+            (Probe::Udp, Status::Reply) => "answered",
+            (Probe::Udp | Probe::Coap, Status::Unreachable) if reply.code == port_unreachable => {
+                "closed"
+            }
+            (Probe::Coap, Status::Reply) => "reset",
+            _ => {
+                let mut stats = self.stats.lock().unwrap();
+                stats.error_count += 1;
+                if self.args.pace != Pace::Flood {
+                    println!(
+                        "{}port {} unreachable: seq {} from {}",
+                        self.prefix(),
+                        self.args.dest_port,
+                        reply.seq,
+                        from
+                    );
+                }
+                return None;
+            }
+        };
+
+        if self.args.pace == Pace::Flood {
+            return Some(reply.elapse);
+        }
+        println!(
+            "{}port {} {} on {}: seq {} time {}.{:03} ms",
+            self.prefix(),
+            self.args.dest_port,
+            state,
+            from,
+            reply.seq,
+            reply.elapse / 1000,
+            reply.elapse % 1000
+        );
+        Some(reply.elapse)
+    }
+
+    fn update_stats(&self, elapse: u32) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.update(elapse);
     }
 
     pub fn print_stats(&self) {
@@ -221,29 +1165,311 @@ impl Ping {
             "\n--- {} ping statistics ---\n{}",
             self.args.host_name, stats
         );
+
+        // a drifting clock shows as a spread between min and max
+        let offsets = self.offsets.lock().unwrap();
+        if !offsets.is_empty() {
+            let min = offsets.iter().copied().fold(f64::MAX, f64::min);
+            let max = offsets.iter().copied().fold(f64::MIN, f64::max);
+            let avg = offsets.iter().sum::<f64>() / offsets.len() as f64;
+            println!(
+                "clock offset min/max/avg {:+.1}/{:+.1}/{:+.1} ms",
+                min, max, avg
+            );
+        }
+
+        let responders = self.responders.lock().unwrap();
+        if !responders.is_empty() {
+            let names: Vec<String> = responders.iter().map(|addr| addr.to_string()).collect();
+            println!("{} responders: {}", responders.len(), names.join(" "));
+        }
     }
 }
 
 ///
-/// Client to Proxy request
-/// | seq(4B) | length(2B) | host length(1B) | host |
-/// Proxy to client reply
-/// | seq(4B) | elapse (4B) | ttl(1B) |
-/// elapse is u32::MAX mean ping timeout
+/// Pings several targets at once, each on a socket and with stats of its
+/// own, and sums them up in a table at the end.
 ///
-fn build_request(buf: &mut BufViewMut, seq: u32, length: u16, addr: &IpAddr) -> usize {
-    buf.clear();
-    buf.write_u32(seq);
-    buf.write_u16(length);
-    match addr {
-        IpAddr::V4(ip) => {
-            buf.write_u8(4);
-            buf.write_bytes(&ip.octets());
+#[derive(Debug)]
+pub struct Pings {
+    pings: Vec<Arc<Ping>>,
+    interval: Duration,
+    aligned: bool,
+}
+
+impl Pings {
+    pub fn new(args: CliArgs) -> Self {
+        let pings = args
+            .targets
+            .iter()
+            .map(|target| Arc::new(Ping::new(args.for_target(target))))
+            .collect();
+        Pings {
+            pings,
+            interval: args.interval,
+            aligned: args.pace == Pace::Aligned,
+        }
+    }
+
+    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
+        // the starts spread over an interval so the probes do not go out
+        // in bursts, aligned probes go out together on purpose
+        let count = self.pings.len() as u32;
+        let runs = self.pings.iter().enumerate().map(|(index, ping)| {
+            let delay = if self.aligned {
+                Duration::ZERO
+            } else {
+                self.interval * index as u32 / count
+            };
+            async move {
+                tokio::time::sleep(delay).await;
+                if let Err(err) = ping.run().await {
+                    println!("{}: ping error: {}", ping.args.host_name, err);
+                }
+            }
+        });
+        futures::future::join_all(runs).await;
+
+        self.print_summary();
+        Ok(())
+    }
+
+    pub fn print_summary(&self) {
+        let width = self
+            .pings
+            .iter()
+            .map(|ping| ping.args.host_name.len())
+            .max()
+            .unwrap_or(0)
+            .max(4);
+        println!(
+            "\n{:<width$} {:>5} {:>5} {:>6} {:>7} {:>7} {:>7}",
+            "Host",
+            "Snt",
+            "Rcv",
+            "Loss%",
+            "Min",
+            "Avg",
+            "Max",
+            width = width
+        );
+
+        let mut total = Stats::new();
+        for ping in &self.pings {
+            let stats = ping.stats.lock().unwrap();
+            let rtt = if stats.rx_count > 0 {
+                format!(
+                    "{:>7.1} {:>7.1} {:>7.1}",
+                    stats.rtt_min as f64 / 1000.0,
+                    stats.avg() / 1000.0,
+                    stats.rtt_max as f64 / 1000.0
+                )
+            } else {
+                format!("{:>7} {:>7} {:>7}", "-", "-", "-")
+            };
+            println!(
+                "{:<width$} {:>5} {:>5} {:>5.1}% {}",
+                ping.args.host_name,
+                stats.tx_count,
+                stats.rx_count,
+                stats.loss(),
+                rtt,
+                width = width
+            );
+            total.tx_count += stats.tx_count;
+            total.rx_count += stats.rx_count;
+        }
+
+        let alive = self
+            .pings
+            .iter()
+            .filter(|ping| ping.exit_code() == 0)
+            .count();
+        println!(
+            "{} targets, {} alive, {} probes tx, {} rx, {:.1}% packets loss",
+            self.pings.len(),
+            alive,
+            total.tx_count,
+            total.rx_count,
+            total.loss()
+        );
+    }
+
+    /// The worst of the targets' `Ping::exit_code`.
+    pub fn exit_code(&self) -> i32 {
+        self.pings
+            .iter()
+            .map(|ping| ping.exit_code())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Asks the proxy which probe types it supports.
+pub async fn capabilities(args: &CliArgs) -> Result<(), Box<dyn Error>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let proxy_addr = SocketAddr::new(args.proxy, args.port);
+    socket.connect(&proxy_addr).await?;
+
+    let mut request = Request::new(1, 0, args.host_addr);
+    request.probe = Probe::Capabilities;
+    let mut buf = [0u8; MAX_MESSAGE];
+    let len = request.encode(&mut buf);
+    socket.send(&buf[..len]).await?;
+
+    let wait = Duration::from_millis(args.timeout.into());
+    match wait_reply(&socket, &mut buf, 1, wait).await? {
+        Some(reply) => {
+            println!(
+                "proxy {} supports {}",
+                proxy_addr,
+                probe_names(&reply.probes)
+            );
+            if let Some(limits) = reply.limits {
+                println!(
+                    "proxy takes {} probes a second, {} in flight",
+                    limits.rate, limits.in_flight
+                );
+            }
         }
-        IpAddr::V6(ip) => {
-            buf.write_u8(16);
-            buf.write_bytes(&ip.octets());
+        None => println!("no answer from proxy {}", proxy_addr),
+    }
+    Ok(())
+}
+
+fn probe_names(probes: &[Probe]) -> String {
+    let names: Vec<&str> = probes.iter().map(|probe| probe.name()).collect();
+    names.join(" ")
+}
+
+/// Waits for the reply to `seq`, replies to earlier probes that came in
+/// after their timeout are dropped. `None` means timeout.
+pub async fn wait_reply(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    seq: u32,
+    wait: Duration,
+) -> io::Result<Option<Reply>> {
+    let deadline = Instant::now() + wait;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let len = match timeout(left, socket.recv(buf)).await {
+            Ok(len) => len?,
+            Err(_) => return Ok(None),
+        };
+
+        if let Ok(reply) = Reply::decode(&buf[..len]) {
+            if reply.seq != seq {
+                continue;
+            }
+            if reply.status == Status::Invalid {
+                let msg = format!("rejected by proxy: {}", reply.error.unwrap_or_default());
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+            return Ok(Some(reply));
         }
     }
-    buf.remaining()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_loss() {
+        let mut stats = Stats::new();
+        assert!(stats.to_string().contains(" 0.0% packets loss"));
+        // the probes that never went out are not lost to the host
+        stats.tx_count = 4;
+        stats.rx_count = 1;
+        stats.lost_count = 2;
+        assert!(stats.to_string().contains(" 50.0% packets loss"));
+        stats.rx_count = 3;
+        assert!(stats.to_string().contains(" 0.0% packets loss"));
+    }
+
+    #[test]
+    fn received_duplicates() {
+        let mut received = Received::new();
+        received.sent(5);
+        assert!(!received.mark(5));
+        assert!(received.mark(5));
+        // its neighbours in the same word are untouched
+        assert!(!received.mark(4));
+        assert!(!received.mark(6));
+    }
+
+    #[test]
+    fn received_wraparound() {
+        let mut received = Received::new();
+        let seq = Received::SIZE - 1;
+        received.sent(seq);
+        assert!(!received.mark(seq));
+
+        // 64k later the same bit is sent again, a reply is no duplicate
+        let again = seq + Received::SIZE;
+        received.sent(again);
+        assert!(!received.mark(again));
+        assert!(received.mark(again));
+
+        // past u32::MAX the sequence wraps onto bit 0
+        received.sent(u32::MAX);
+        assert!(!received.mark(u32::MAX));
+        received.sent(u32::MAX.wrapping_add(1));
+        assert!(!received.mark(0));
+    }
+
+    #[test]
+    fn gaps_seeded() {
+        let mean = Duration::from_millis(100);
+        let draw = |seed| {
+            let mut gaps = Gaps::new(seed);
+            (0..8).map(|_| gaps.next(mean)).collect::<Vec<_>>()
+        };
+        assert_eq!(draw(1), draw(1));
+        assert_ne!(draw(1), draw(2));
+        // small seeds start out spread, not with a run of long gaps
+        assert!(draw(0).iter().any(|gap| *gap < mean));
+    }
+
+    #[test]
+    fn gaps_mean() {
+        let mean = Duration::from_millis(100);
+        let mut gaps = Gaps::new(42);
+        let n = 100_000;
+        let total: Duration = (0..n).map(|_| gaps.next(mean)).sum();
+        let average = total.as_secs_f64() / n as f64;
+        assert!((average - 0.1).abs() < 0.002, "{}", average);
+    }
+
+    #[test]
+    fn aligned_on_wall_clock() {
+        let gap = Duration::from_millis(250);
+        let now = Instant::now();
+        let at = aligned(now, gap);
+        assert!(at >= now && at < now + gap);
+
+        // where `at` is on the wall clock, give or take the time between
+        // the two clock reads
+        let wall = SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+            + at.saturating_duration_since(Instant::now());
+        let phase = wall.as_nanos() % gap.as_nanos();
+        let slack = Duration::from_millis(20).as_nanos();
+        assert!(phase < slack || phase > gap.as_nanos() - slack, "{}", phase);
+
+        // a later start lines up on a later multiple
+        let later = aligned(at + Duration::from_millis(1), gap) - at;
+        assert!(later.abs_diff(gap).as_nanos() < slack, "{:?}", later);
+    }
+
+    #[test]
+    fn aligned_past() {
+        // a start in the past is taken from now
+        let now = Instant::now();
+        let past = now.checked_sub(Duration::from_secs(5)).unwrap_or(now);
+        assert!(aligned(past, Duration::from_secs(1)) >= now);
+        // a zero gap does not wait
+        let at = aligned(now, Duration::ZERO);
+        assert!(at <= Instant::now());
+    }
 }
@@ -0,0 +1,133 @@
+use std::{error::Error, net::SocketAddr};
+use tokio::{net::UdpSocket, time::Duration};
+
+use ping_proxy::proto::{self, Request, Status, MAX_MESSAGE};
+
+use crate::cli::{CliArgs, CliArgumentError};
+use crate::ping::wait_reply;
+
+/// Probes that time out this many times in a row count as too big.
+const TRIES: u8 = 2;
+
+#[derive(Debug)]
+enum Outcome {
+    Fits,
+    TooBig(Option<u16>),
+    Lost,
+}
+
+#[derive(Debug)]
+pub struct Pmtu {
+    args: CliArgs,
+}
+
+impl Pmtu {
+    pub fn new(args: CliArgs) -> Self {
+        Pmtu { args }
+    }
+
+    ///
+    /// Binary search on the ICMP payload size with DF set, only a reply
+    /// from the host proves a size fits. A "too big" error carrying the
+    /// next hop MTU narrows the upper bound to it at once, any other error
+    /// and a probe that times out every try to the size below.
+    ///
+    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
+        println!("pmtu to {} ({})", self.args.host_name, self.args.host_addr);
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let proxy_addr = SocketAddr::new(self.args.proxy, self.args.port);
+        socket.connect(&proxy_addr).await?;
+
+        let header = self.args.header_len();
+        let mut seq = 0;
+        let mut lo = self.args.length;
+        let mut hi = proto::max_length(&self.args.host_addr);
+
+        if !matches!(self.probe(&socket, &mut seq, lo).await?, Outcome::Fits) {
+            let msg = format!("no reply with {} bytes", lo);
+            return Err(Box::new(CliArgumentError::new(&msg)));
+        }
+
+        while lo < hi {
+            let mid = lo + (hi - lo).div_ceil(2);
+            match self.probe(&socket, &mut seq, mid).await? {
+                Outcome::Fits => lo = mid,
+                Outcome::TooBig(Some(mtu)) => {
+                    hi = (mid - 1).min(mtu.saturating_sub(header)).max(lo);
+                }
+                Outcome::TooBig(None) | Outcome::Lost => hi = mid - 1,
+            }
+        }
+
+        println!("path mtu {} ({} bytes of payload)", lo + header, lo);
+
+        Ok(())
+    }
+
+    async fn probe(
+        &self,
+        socket: &UdpSocket,
+        seq: &mut u32,
+        length: u16,
+    ) -> Result<Outcome, Box<dyn Error>> {
+        let mut buf = [0u8; MAX_MESSAGE];
+        let wait = Duration::from_millis(self.args.timeout as u64);
+
+        for _ in 0..TRIES {
+            *seq += 1;
+            let mut request = Request::new(*seq, length, self.args.host_addr);
+            request.timeout = self.args.timeout;
+            request.tos = self.args.tos;
+            request.payload = self.args.payload.clone();
+            request.vlan = self.args.vlan;
+            request.netns = self.args.netns.clone();
+            request.df = true;
+            let len = request.encode(&mut buf);
+            socket.send(&buf[..len]).await?;
+
+            let reply = match wait_reply(socket, &mut buf, *seq, wait).await? {
+                Some(reply) => reply,
+                None => continue,
+            };
+
+            if reply.status == Status::TooBig {
+                if !self.args.quiet {
+                    match (reply.mtu, reply.from) {
+                        (Some(mtu), Some(from)) => {
+                            println!("{} bytes: too big, mtu {} from {}", length, mtu, from)
+                        }
+                        (Some(mtu), None) => println!("{} bytes: too big, mtu {}", length, mtu),
+                        _ => println!("{} bytes: too big", length),
+                    }
+                }
+                return Ok(Outcome::TooBig(reply.mtu));
+            }
+            if reply.status != Status::Reply {
+                if !self.args.quiet {
+                    let error = match reply.status {
+                        Status::TimeExceeded => "time exceeded",
+                        _ => "unreachable",
+                    };
+                    let from = reply.from.unwrap_or(self.args.host_addr);
+                    println!("{} bytes: {} from {}", length, error, from);
+                }
+                return Ok(Outcome::Lost);
+            }
+
+            if !self.args.quiet {
+                println!(
+                    "{} bytes: ok, time {}.{:03} ms",
+                    length,
+                    reply.elapse / 1000,
+                    reply.elapse % 1000
+                );
+            }
+            return Ok(Outcome::Fits);
+        }
+
+        if !self.args.quiet {
+            println!("{} bytes: no reply", length);
+        }
+        Ok(Outcome::Lost)
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    error::Error,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+};
+use tokio::{net::UdpSocket, time::Duration};
+
+use ping_proxy::proto::{Reply, Request, Status, MAX_MESSAGE};
+
+use crate::cli::CliArgs;
+use crate::ping::wait_reply;
+
+#[derive(Debug)]
+pub struct Traceroute {
+    args: CliArgs,
+}
+
+impl Traceroute {
+    pub fn new(args: CliArgs) -> Self {
+        Traceroute { args }
+    }
+
+    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
+        println!(
+            "traceroute to {} ({}), {} hops max, {} byte packets",
+            self.args.host_name,
+            self.args.host_addr,
+            self.args.max_hops,
+            self.args.length as u32 + self.args.header_len() as u32
+        );
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let proxy_addr = SocketAddr::new(self.args.proxy, self.args.port);
+        socket.connect(&proxy_addr).await?;
+
+        let mut buf = [0u8; MAX_MESSAGE];
+        let mut seq = 0;
+
+        for ttl in 1..=self.args.max_hops {
+            print!("{:2} ", ttl);
+            let mut last_from: Option<IpAddr> = None;
+            let mut reached = false;
+
+            for _ in 0..self.args.queries {
+                seq += 1;
+                let mut request = Request::new(seq, self.args.length, self.args.host_addr);
+                request.probe = self.args.probe;
+                request.ttl = Some(ttl);
+                request.port = self.args.dest_port;
+                request.timeout = self.args.timeout;
+                request.tos = self.args.tos;
+                request.payload = self.args.payload.clone();
+                request.vlan = self.args.vlan;
+                request.netns = self.args.netns.clone();
+                let len = request.encode(&mut buf);
+
+                if let Err(err) = socket.send(&buf[..len]).await {
+                    print!(" *");
+                    if self.args.show_error {
+                        print!(" (send to proxy error: {})", err);
+                    }
+                    continue;
+                }
+
+                let wait = Duration::from_millis(self.args.timeout as u64);
+                match wait_reply(&socket, &mut buf, seq, wait).await {
+                    Ok(Some(reply)) => {
+                        if let Some(from) = reply.from {
+                            if reply.from != last_from {
+                                print!(" {}", from);
+                                last_from = reply.from;
+                            }
+                        }
+                        print!("  {}.{:03} ms", reply.elapse / 1000, reply.elapse % 1000);
+                        if let Some(flag) = self.unreachable_flag(&reply) {
+                            print!(" {}", flag);
+                        }
+                        if reply.status != Status::TimeExceeded {
+                            reached = true;
+                        }
+                    }
+                    Ok(None) => print!(" *"),
+                    Err(err) => {
+                        print!(" *");
+                        if self.args.show_error {
+                            print!(" (recv from proxy error: {})", err);
+                        }
+                    }
+                }
+                let _ = std::io::stdout().flush();
+            }
+
+            println!();
+            if reached {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    //
+    // the classic traceroute annotations, port unreachable is the normal
+    // end of a UDP trace and is not flagged
+    //
+    fn unreachable_flag(&self, reply: &Reply) -> Option<&'static str> {
+        if reply.status != Status::Unreachable {
+            return None;
+        }
+
+        let flag = if self.args.host_addr.is_ipv4() {
+            match reply.code {
+                0 => "!N",
+                1 => "!H",
+                2 => "!P",
+                3 => return None,
+                4 => "!F",
+                9 | 10 | 13 => "!X",
+                _ => "!",
+            }
+        } else {
+            match reply.code {
+                0 => "!N",
+                1 => "!X",
+                3 => "!H",
+                4 => return None,
+                _ => "!",
+            }
+        };
+        Some(flag)
+    }
+}
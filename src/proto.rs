@@ -0,0 +1,1338 @@
+use std::net::IpAddr;
+
+use buf_view::{BufView, BufViewMut};
+
+///
+/// Client to Proxy request
+/// | seq(4B) | length(2B) | host length(1B) | host | options |
+/// length is the probe payload size, what follows the ICMP or UDP header
+/// Proxy to client reply
+/// | seq(4B) | elapse (4B) | ttl(1B) | options |
+/// elapse is u32::MAX mean ping timeout
+///
+/// Every option is encoded as
+/// | kind(1B) | length(2B) | value |
+/// unknown kinds are skipped, so a message without options is the
+/// original fixed layout.
+///
+pub const MAX_MESSAGE: usize = 1024;
+
+/// | probe(1B) |
+pub const REQ_PROBE: u8 = 1;
+/// | ttl(1B) |
+pub const REQ_TTL: u8 = 2;
+/// | port(2B) |
+pub const REQ_PORT: u8 = 3;
+/// | timeout millis(2B) |
+pub const REQ_TIMEOUT: u8 = 4;
+/// | tos or traffic class(1B) |
+pub const REQ_TOS: u8 = 5;
+/// | don't fragment(1B) |
+pub const REQ_DF: u8 = 6;
+/// | payload mode(1B) | pattern, seed or bytes |
+pub const REQ_PAYLOAD: u8 = 7;
+/// | interface name |
+pub const REQ_IFACE: u8 = 8;
+/// | unicast(1B) |, solicit the target itself instead of its
+/// solicited-node multicast group
+pub const REQ_UNICAST: u8 = 9;
+/// | priority(3b) | drop eligible(1b) | vlan id(12b) |, the 802.1Q tag
+/// the proxy sends the probe with
+pub const REQ_VLAN: u8 = 10;
+/// | namespace name |, one of the network namespaces the proxy opened
+pub const REQ_NETNS: u8 = 11;
+/// | multi(1B) |, a broadcast or multicast probe, every responder is
+/// answered
+pub const REQ_MULTI: u8 = 12;
+
+/// | host length(1B) | host |
+pub const REP_FROM: u8 = 1;
+/// | status(1B) | code(1B) |
+pub const REP_STATUS: u8 = 2;
+/// | tos or traffic class(1B) |
+pub const REP_TOS: u8 = 3;
+/// | next hop mtu(2B) |
+pub const REP_MTU: u8 = 4;
+/// | clock source(1B) |
+pub const REP_CLOCK: u8 = 5;
+/// | reason |, why an invalid request was rejected
+pub const REP_ERROR: u8 = 6;
+/// | link layer address(6B) |
+pub const REP_MAC: u8 = 7;
+/// | neighbor advertisement flags(1B) |, router 0x80, solicited 0x40,
+/// override 0x20
+pub const REP_ND_FLAGS: u8 = 8;
+/// | probe type(1B) | ... |, the probe types the proxy supports
+pub const REP_PROBES: u8 = 9;
+/// | originate(4B) | receive(4B) | transmit(4B) | arrival(4B) |, ICMP
+/// timestamps and when the reply came back to the proxy
+pub const REP_TIMESTAMPS: u8 = 10;
+/// | probes per second(4B) | probes in flight(2B) |, what the proxy takes
+/// from one client
+pub const REP_LIMITS: u8 = 11;
+/// | duplicate(1B) |, another reply to a probe that was answered already
+pub const REP_DUPLICATE: u8 = 12;
+
+/// `code` of an `Invalid` reply to a probe the proxy took but failed to
+/// send, it says nothing of the probes after it.
+pub const INVALID_SEND: u8 = 1;
+
+pub const DEFAULT_TIMEOUT: u16 = 4000;
+
+/// Longest `ping -p` style pattern.
+pub const MAX_PATTERN: usize = 16;
+/// Most client supplied payload bytes that fit in a request.
+pub const MAX_PAYLOAD_BYTES: usize = 512;
+/// Longest interface name, IFNAMSIZ without the terminating zero.
+pub const MAX_IFACE: usize = 15;
+/// Longest network namespace name.
+pub const MAX_NETNS: usize = 64;
+
+#[derive(Debug)]
+pub enum ProtoError {
+    Truncated,
+    Host,
+    Probe,
+    Payload,
+    Length,
+    Interface,
+    Mac,
+    Vlan,
+    Namespace,
+}
+
+impl std::fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtoError::Truncated => write!(f, "Truncated message"),
+            ProtoError::Host => write!(f, "Invalid host length"),
+            ProtoError::Probe => write!(f, "Unknown probe type"),
+            ProtoError::Payload => write!(f, "Invalid payload"),
+            ProtoError::Length => write!(f, "Invalid packet length"),
+            ProtoError::Interface => write!(f, "Invalid interface"),
+            ProtoError::Mac => write!(f, "Invalid MAC address"),
+            ProtoError::Vlan => write!(f, "Invalid VLAN"),
+            ProtoError::Namespace => write!(f, "Invalid namespace"),
+        }
+    }
+}
+
+impl std::error::Error for ProtoError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Probe {
+    Echo,
+    Udp,
+    Tcp,
+    Arp,
+    Ndp,
+    Coap,
+    Timestamp,
+    /// asks the proxy which probe types it supports instead of probing
+    Capabilities,
+}
+
+impl Probe {
+    pub fn from_u8(value: u8) -> Option<Probe> {
+        match value {
+            0 => Some(Probe::Echo),
+            1 => Some(Probe::Udp),
+            2 => Some(Probe::Tcp),
+            3 => Some(Probe::Arp),
+            4 => Some(Probe::Ndp),
+            5 => Some(Probe::Coap),
+            6 => Some(Probe::Timestamp),
+            255 => Some(Probe::Capabilities),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Probe::Echo => 0,
+            Probe::Udp => 1,
+            Probe::Tcp => 2,
+            Probe::Arp => 3,
+            Probe::Ndp => 4,
+            Probe::Coap => 5,
+            Probe::Timestamp => 6,
+            Probe::Capabilities => 255,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Probe::Echo => "icmp",
+            Probe::Udp => "udp",
+            Probe::Tcp => "tcp",
+            Probe::Arp => "arp",
+            Probe::Ndp => "ndp",
+            Probe::Coap => "coap",
+            Probe::Timestamp => "timestamp",
+            Probe::Capabilities => "capabilities",
+        }
+    }
+}
+
+/// What the probe got back. `code` in `Reply` carries the ICMP code for
+/// `Unreachable`, `TooBig` is "fragmentation needed" or "packet too big".
+/// `Invalid` is the proxy's own answer, with code `INVALID_SEND` for a
+/// probe it failed to send and 0 for one it will not take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Reply,
+    TimeExceeded,
+    Unreachable,
+    Refused,
+    TooBig,
+    Invalid,
+}
+
+impl Status {
+    pub fn from_u8(value: u8) -> Status {
+        match value {
+            1 => Status::TimeExceeded,
+            2 => Status::Unreachable,
+            3 => Status::Refused,
+            4 => Status::TooBig,
+            5 => Status::Invalid,
+            _ => Status::Reply,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Status::Reply => 0,
+            Status::TimeExceeded => 1,
+            Status::Unreachable => 2,
+            Status::Refused => 3,
+            Status::TooBig => 4,
+            Status::Invalid => 5,
+        }
+    }
+}
+
+/// How hard one client may drive the proxy, requests beyond either limit
+/// are rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub rate: u32,
+    pub in_flight: u16,
+}
+
+/// Milliseconds in a day, ICMP timestamps count from midnight UTC.
+const DAY_MILLIS: i64 = 86_400_000;
+
+/// ICMP timestamps in milliseconds since midnight UTC. The proxy's clock
+/// took `originate` and `arrival`, the target's `receive` and `transmit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamps {
+    pub originate: u32,
+    pub receive: u32,
+    pub transmit: u32,
+    pub arrival: u32,
+}
+
+impl Timestamps {
+    /// The high bit marks a target clock that is not milliseconds since
+    /// midnight UTC, its timestamps only compare with each other.
+    pub fn standard(&self) -> bool {
+        (self.receive | self.transmit) & 0x8000_0000 == 0
+    }
+
+    /// Target clock minus proxy clock, assuming the path takes as long
+    /// both ways.
+    pub fn offset(&self) -> f64 {
+        (self.forward() + day_diff(self.transmit, self.arrival)) as f64 / 2.0
+    }
+
+    /// The one way delay to the target as the two clocks see it.
+    pub fn forward(&self) -> i64 {
+        day_diff(self.receive, self.originate)
+    }
+
+    /// The one way delay back from the target as the two clocks see it.
+    pub fn back(&self) -> i64 {
+        day_diff(self.arrival, self.transmit)
+    }
+}
+
+/// `a - b` across midnight, taken as the shorter way round.
+fn day_diff(a: u32, b: u32) -> i64 {
+    let diff = (a as i64 - b as i64).rem_euclid(DAY_MILLIS);
+    if diff > DAY_MILLIS / 2 {
+        diff - DAY_MILLIS
+    } else {
+        diff
+    }
+}
+
+/// An Ethernet address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl std::fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let m = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        )
+    }
+}
+
+impl std::str::FromStr for MacAddr {
+    type Err = ProtoError;
+
+    /// Six colon or dash separated hex octets.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mac = [0u8; 6];
+        let mut octets = s.split([':', '-']);
+        for octet in mac.iter_mut() {
+            let value = octets.next().ok_or(ProtoError::Mac)?;
+            if value.is_empty() || value.len() > 2 {
+                return Err(ProtoError::Mac);
+            }
+            *octet = u8::from_str_radix(value, 16).map_err(|_| ProtoError::Mac)?;
+        }
+        if octets.next().is_some() {
+            return Err(ProtoError::Mac);
+        }
+        Ok(MacAddr(mac))
+    }
+}
+
+/// An 802.1Q tag, VLAN ids 0 and 4095 are reserved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vlan {
+    pub id: u16,
+    /// priority code point, 0 to 7
+    pub pcp: u8,
+}
+
+impl Vlan {
+    pub const MAX_ID: u16 = 4094;
+
+    /// The tag control information, drop eligible is never set.
+    pub fn tci(&self) -> u16 {
+        ((self.pcp as u16 & 0x7) << 13) | (self.id & 0xFFF)
+    }
+
+    pub fn from_tci(tci: u16) -> Self {
+        Vlan {
+            id: tci & 0xFFF,
+            pcp: (tci >> 13) as u8,
+        }
+    }
+}
+
+/// How the proxy fills the probe payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    /// 0, 1, 2, ... wrapping at 255
+    Sequence,
+    /// a pattern of up to `MAX_PATTERN` bytes repeated
+    Pattern(Vec<u8>),
+    /// pseudo random bytes, the same seed gives the same bytes
+    Random(u64),
+    Zeros,
+    /// the bytes as given, zeros after them
+    Bytes(Vec<u8>),
+}
+
+impl Payload {
+    pub fn fill(&self, buf: &mut [u8]) {
+        match self {
+            Payload::Sequence => {
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte = (i & 0xFF) as u8;
+                }
+            }
+            Payload::Pattern(pattern) => {
+                for (byte, value) in buf.iter_mut().zip(pattern.iter().cycle()) {
+                    *byte = *value;
+                }
+            }
+            Payload::Random(seed) => {
+                // xorshift64, zero is its only fixed point
+                let mut state = if *seed == 0 {
+                    0x9E3779B97F4A7C15
+                } else {
+                    *seed
+                };
+                for chunk in buf.chunks_mut(8) {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    chunk.copy_from_slice(&state.to_be_bytes()[..chunk.len()]);
+                }
+            }
+            Payload::Zeros => buf.fill(0),
+            Payload::Bytes(bytes) => {
+                let len = bytes.len().min(buf.len());
+                buf[..len].copy_from_slice(&bytes[..len]);
+                buf[len..].fill(0);
+            }
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut value = Vec::new();
+        match self {
+            Payload::Sequence => value.push(0),
+            Payload::Pattern(pattern) => {
+                value.push(1);
+                value.extend_from_slice(pattern);
+            }
+            Payload::Random(seed) => {
+                value.push(2);
+                value.extend_from_slice(&seed.to_be_bytes());
+            }
+            Payload::Zeros => value.push(3),
+            Payload::Bytes(bytes) => {
+                value.push(4);
+                value.extend_from_slice(bytes);
+            }
+        }
+        value
+    }
+
+    fn decode(value: &[u8]) -> Result<Payload, ProtoError> {
+        let (mode, data) = value.split_first().ok_or(ProtoError::Payload)?;
+        match mode {
+            0 => Ok(Payload::Sequence),
+            1 if !data.is_empty() && data.len() <= MAX_PATTERN => {
+                Ok(Payload::Pattern(data.to_vec()))
+            }
+            2 if data.len() == 8 => {
+                let mut seed = [0u8; 8];
+                seed.copy_from_slice(data);
+                Ok(Payload::Random(u64::from_be_bytes(seed)))
+            }
+            3 => Ok(Payload::Zeros),
+            4 if data.len() <= MAX_PAYLOAD_BYTES => Ok(Payload::Bytes(data.to_vec())),
+            _ => Err(ProtoError::Payload),
+        }
+    }
+}
+
+/// Where the times an elapse is computed from were taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    User,
+    Software,
+    Hardware,
+}
+
+impl Clock {
+    pub fn from_u8(value: u8) -> Clock {
+        match value {
+            1 => Clock::Software,
+            2 => Clock::Hardware,
+            _ => Clock::User,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Clock::User => 0,
+            Clock::Software => 1,
+            Clock::Hardware => 2,
+        }
+    }
+}
+
+impl std::fmt::Display for Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Clock::User => write!(f, "user"),
+            Clock::Software => write!(f, "kernel"),
+            Clock::Hardware => write!(f, "hardware"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub seq: u32,
+    pub length: u16,
+    pub host: IpAddr,
+    pub probe: Probe,
+    pub ttl: Option<u8>,
+    pub port: u16,
+    pub timeout: u16,
+    pub tos: Option<u8>,
+    pub df: bool,
+    pub payload: Payload,
+    /// the interface link layer probes go out on, the proxy picks the one
+    /// on the target's subnet when there is none
+    pub iface: Option<String>,
+    pub unicast: bool,
+    pub vlan: Option<Vlan>,
+    /// the network namespace the probe goes out from, the proxy's own when
+    /// there is none
+    pub netns: Option<String>,
+    pub multi: bool,
+}
+
+impl Request {
+    pub fn new(seq: u32, length: u16, host: IpAddr) -> Self {
+        Request {
+            seq,
+            length,
+            host,
+            probe: Probe::Echo,
+            ttl: None,
+            port: 0,
+            timeout: DEFAULT_TIMEOUT,
+            tos: None,
+            df: false,
+            payload: Payload::Sequence,
+            iface: None,
+            unicast: false,
+            vlan: None,
+            netns: None,
+            multi: false,
+        }
+    }
+
+    /// Checks what decoding alone does not, the proxy refuses a request
+    /// that fails here.
+    pub fn validate(&self) -> Result<(), ProtoError> {
+        if self.length > max_length(&self.host) {
+            return Err(ProtoError::Length);
+        }
+        if matches!(self.probe, Probe::Arp | Probe::Timestamp) && !self.host.is_ipv4() {
+            return Err(ProtoError::Probe);
+        }
+        if self.probe == Probe::Ndp && !self.host.is_ipv6() {
+            return Err(ProtoError::Probe);
+        }
+        if let Some(vlan) = self.vlan {
+            if vlan.id == 0 || vlan.id > Vlan::MAX_ID || vlan.pcp > 7 {
+                return Err(ProtoError::Vlan);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut buf = BufViewMut::wrap(buf);
+        buf.write_u32(self.seq);
+        buf.write_u16(self.length);
+        write_host(&mut buf, &self.host);
+
+        if self.probe != Probe::Echo {
+            write_option(&mut buf, REQ_PROBE, &[self.probe.as_u8()]);
+        }
+        if let Some(ttl) = self.ttl {
+            write_option(&mut buf, REQ_TTL, &[ttl]);
+        }
+        if self.port != 0 {
+            write_option(&mut buf, REQ_PORT, &self.port.to_be_bytes());
+        }
+        write_option(&mut buf, REQ_TIMEOUT, &self.timeout.to_be_bytes());
+        if let Some(tos) = self.tos {
+            write_option(&mut buf, REQ_TOS, &[tos]);
+        }
+        if self.df {
+            write_option(&mut buf, REQ_DF, &[1]);
+        }
+        if self.payload != Payload::Sequence {
+            write_option(&mut buf, REQ_PAYLOAD, &self.payload.encode());
+        }
+        if let Some(iface) = &self.iface {
+            write_option(&mut buf, REQ_IFACE, iface.as_bytes());
+        }
+        if self.unicast {
+            write_option(&mut buf, REQ_UNICAST, &[1]);
+        }
+        if let Some(vlan) = self.vlan {
+            write_option(&mut buf, REQ_VLAN, &vlan.tci().to_be_bytes());
+        }
+        if let Some(netns) = &self.netns {
+            write_option(&mut buf, REQ_NETNS, netns.as_bytes());
+        }
+        if self.multi {
+            write_option(&mut buf, REQ_MULTI, &[1]);
+        }
+
+        buf.remaining()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Request, ProtoError> {
+        let mut buf = BufView::wrap_with(data, 0, data.len());
+        if buf.remaining() < 7 {
+            return Err(ProtoError::Truncated);
+        }
+
+        let seq = buf.read_u32();
+        let length = buf.read_u16();
+        let host = read_host(&mut buf)?;
+        let mut request = Request::new(seq, length, host);
+
+        let mut index = buf.reader_index();
+        while let Some((kind, value)) = read_option(data, &mut index)? {
+            match kind {
+                REQ_PROBE if !value.is_empty() => {
+                    request.probe = Probe::from_u8(value[0]).ok_or(ProtoError::Probe)?;
+                }
+                REQ_TTL if !value.is_empty() => request.ttl = Some(value[0]),
+                REQ_PORT if value.len() >= 2 => {
+                    request.port = u16::from_be_bytes([value[0], value[1]]);
+                }
+                REQ_TIMEOUT if value.len() >= 2 => {
+                    request.timeout = u16::from_be_bytes([value[0], value[1]]);
+                }
+                REQ_TOS if !value.is_empty() => request.tos = Some(value[0]),
+                REQ_DF if !value.is_empty() => request.df = value[0] != 0,
+                REQ_PAYLOAD => request.payload = Payload::decode(value)?,
+                REQ_IFACE => {
+                    let iface = std::str::from_utf8(value).map_err(|_| ProtoError::Interface)?;
+                    if iface.is_empty() || iface.len() > MAX_IFACE || iface.contains('\0') {
+                        return Err(ProtoError::Interface);
+                    }
+                    request.iface = Some(iface.to_string());
+                }
+                REQ_UNICAST if !value.is_empty() => request.unicast = value[0] != 0,
+                REQ_VLAN if value.len() >= 2 => {
+                    let tci = u16::from_be_bytes([value[0], value[1]]);
+                    request.vlan = Some(Vlan::from_tci(tci));
+                }
+                REQ_MULTI if !value.is_empty() => request.multi = value[0] != 0,
+                REQ_NETNS => {
+                    let netns = std::str::from_utf8(value).map_err(|_| ProtoError::Namespace)?;
+                    if !netns_valid(netns) {
+                        return Err(ProtoError::Namespace);
+                    }
+                    request.netns = Some(netns.to_string());
+                }
+                _ => {}
+            }
+        }
+
+        Ok(request)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub seq: u32,
+    pub elapse: u32,
+    pub ttl: u8,
+    pub from: Option<IpAddr>,
+    pub status: Status,
+    pub code: u8,
+    pub tos: Option<u8>,
+    pub mtu: Option<u16>,
+    pub clock: Clock,
+    pub error: Option<String>,
+    pub mac: Option<MacAddr>,
+    pub nd_flags: Option<u8>,
+    pub probes: Vec<Probe>,
+    pub timestamps: Option<Timestamps>,
+    pub limits: Option<Limits>,
+    pub duplicate: bool,
+}
+
+impl Reply {
+    pub fn new(seq: u32, elapse: u32, ttl: u8) -> Self {
+        Reply {
+            seq,
+            elapse,
+            ttl,
+            from: None,
+            status: Status::Reply,
+            code: 0,
+            tos: None,
+            mtu: None,
+            clock: Clock::User,
+            error: None,
+            mac: None,
+            nd_flags: None,
+            probes: Vec::new(),
+            timestamps: None,
+            limits: None,
+            duplicate: false,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut buf = BufViewMut::wrap(buf);
+        buf.write_u32(self.seq);
+        buf.write_u32(self.elapse);
+        buf.write_u8(self.ttl);
+
+        if let Some(from) = self.from {
+            let mut host = [0u8; 17];
+            let len = {
+                let mut host = BufViewMut::wrap(&mut host);
+                write_host(&mut host, &from);
+                host.remaining()
+            };
+            write_option(&mut buf, REP_FROM, &host[..len]);
+        }
+        if self.status != Status::Reply || self.code != 0 {
+            write_option(&mut buf, REP_STATUS, &[self.status.as_u8(), self.code]);
+        }
+        if let Some(tos) = self.tos {
+            write_option(&mut buf, REP_TOS, &[tos]);
+        }
+        if let Some(mtu) = self.mtu {
+            write_option(&mut buf, REP_MTU, &mtu.to_be_bytes());
+        }
+        if self.clock != Clock::User {
+            write_option(&mut buf, REP_CLOCK, &[self.clock.as_u8()]);
+        }
+        if let Some(error) = &self.error {
+            write_option(&mut buf, REP_ERROR, error.as_bytes());
+        }
+        if let Some(mac) = &self.mac {
+            write_option(&mut buf, REP_MAC, &mac.0);
+        }
+        if let Some(flags) = self.nd_flags {
+            write_option(&mut buf, REP_ND_FLAGS, &[flags]);
+        }
+        if !self.probes.is_empty() {
+            let probes: Vec<u8> = self.probes.iter().map(|probe| probe.as_u8()).collect();
+            write_option(&mut buf, REP_PROBES, &probes);
+        }
+        if let Some(stamps) = &self.timestamps {
+            let mut value = [0u8; 16];
+            value[0..4].copy_from_slice(&stamps.originate.to_be_bytes());
+            value[4..8].copy_from_slice(&stamps.receive.to_be_bytes());
+            value[8..12].copy_from_slice(&stamps.transmit.to_be_bytes());
+            value[12..16].copy_from_slice(&stamps.arrival.to_be_bytes());
+            write_option(&mut buf, REP_TIMESTAMPS, &value);
+        }
+        if let Some(limits) = &self.limits {
+            let mut value = [0u8; 6];
+            value[0..4].copy_from_slice(&limits.rate.to_be_bytes());
+            value[4..6].copy_from_slice(&limits.in_flight.to_be_bytes());
+            write_option(&mut buf, REP_LIMITS, &value);
+        }
+        if self.duplicate {
+            write_option(&mut buf, REP_DUPLICATE, &[1]);
+        }
+
+        buf.remaining()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Reply, ProtoError> {
+        let mut buf = BufView::wrap_with(data, 0, data.len());
+        if buf.remaining() < 9 {
+            return Err(ProtoError::Truncated);
+        }
+
+        let seq = buf.read_u32();
+        let elapse = buf.read_u32();
+        let ttl = buf.read_u8();
+        let mut reply = Reply::new(seq, elapse, ttl);
+
+        let mut index = buf.reader_index();
+        while let Some((kind, value)) = read_option(data, &mut index)? {
+            match kind {
+                REP_FROM => {
+                    let mut host = BufView::wrap_with(value, 0, value.len());
+                    reply.from = Some(read_host(&mut host)?);
+                }
+                REP_STATUS if value.len() >= 2 => {
+                    reply.status = Status::from_u8(value[0]);
+                    reply.code = value[1];
+                }
+                REP_TOS if !value.is_empty() => reply.tos = Some(value[0]),
+                REP_MTU if value.len() >= 2 => {
+                    reply.mtu = Some(u16::from_be_bytes([value[0], value[1]]));
+                }
+                REP_CLOCK if !value.is_empty() => reply.clock = Clock::from_u8(value[0]),
+                REP_ERROR => reply.error = Some(String::from_utf8_lossy(value).into_owned()),
+                REP_MAC if value.len() >= 6 => {
+                    let mut mac = [0u8; 6];
+                    mac.copy_from_slice(&value[..6]);
+                    reply.mac = Some(MacAddr(mac));
+                }
+                REP_ND_FLAGS if !value.is_empty() => reply.nd_flags = Some(value[0]),
+                // types of a newer proxy this side does not know are left out
+                REP_PROBES => {
+                    reply.probes = value.iter().filter_map(|&v| Probe::from_u8(v)).collect();
+                }
+                REP_TIMESTAMPS if value.len() >= 16 => {
+                    let stamp = |i: usize| {
+                        u32::from_be_bytes([value[i], value[i + 1], value[i + 2], value[i + 3]])
+                    };
+                    reply.timestamps = Some(Timestamps {
+                        originate: stamp(0),
+                        receive: stamp(4),
+                        transmit: stamp(8),
+                        arrival: stamp(12),
+                    });
+                }
+                REP_LIMITS if value.len() >= 6 => {
+                    reply.limits = Some(Limits {
+                        rate: u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+                        in_flight: u16::from_be_bytes([value[4], value[5]]),
+                    });
+                }
+                REP_DUPLICATE if !value.is_empty() => reply.duplicate = value[0] != 0,
+                _ => {}
+            }
+        }
+
+        Ok(reply)
+    }
+}
+
+/// The largest payload an ICMP or UDP probe to `host` can carry, an IPv4
+/// packet holds at most 65535 bytes with its header, the IPv6 payload
+/// length does not count the fixed header.
+pub fn max_length(host: &IpAddr) -> u16 {
+    match host {
+        IpAddr::V4(_) => 65535 - 20 - 8,
+        IpAddr::V6(_) => 65535 - 8,
+    }
+}
+
+/// A namespace name is a file under /var/run/netns on the proxy, it must
+/// not reach out of that directory.
+pub fn netns_valid(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NETNS
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\0'])
+}
+
+fn write_host(buf: &mut BufViewMut, addr: &IpAddr) {
+    match addr {
+        IpAddr::V4(ip) => {
+            buf.write_u8(4);
+            buf.write_bytes(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.write_u8(16);
+            buf.write_bytes(&ip.octets());
+        }
+    }
+}
+
+fn read_host(buf: &mut BufView) -> Result<IpAddr, ProtoError> {
+    if buf.remaining() < 1 {
+        return Err(ProtoError::Truncated);
+    }
+
+    let host_len = buf.read_u8() as usize;
+    if host_len != 4 && host_len != 16 {
+        return Err(ProtoError::Host);
+    }
+    if buf.remaining() < host_len {
+        return Err(ProtoError::Truncated);
+    }
+
+    if host_len == 4 {
+        let mut v4 = [0u8; 4];
+        buf.read_bytes(&mut v4);
+        Ok(IpAddr::from(v4))
+    } else {
+        let mut v6 = [0u8; 16];
+        buf.read_bytes(&mut v6);
+        Ok(IpAddr::from(v6))
+    }
+}
+
+fn write_option(buf: &mut BufViewMut, kind: u8, value: &[u8]) {
+    buf.write_u8(kind);
+    buf.write_u16(value.len() as u16);
+    buf.write_bytes(value);
+}
+
+fn read_option<'a>(buf: &'a [u8], index: &mut usize) -> Result<Option<(u8, &'a [u8])>, ProtoError> {
+    let left = &buf[*index..];
+    if left.is_empty() {
+        return Ok(None);
+    }
+    if left.len() < 3 {
+        return Err(ProtoError::Truncated);
+    }
+
+    let kind = left[0];
+    let len = u16::from_be_bytes([left[1], left[2]]) as usize;
+    if left.len() < len + 3 {
+        return Err(ProtoError::Truncated);
+    }
+
+    *index += len + 3;
+    Ok(Some((kind, &left[3..len + 3])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn request_round_trip(request: &Request) -> Request {
+        let mut buf = [0u8; MAX_MESSAGE];
+        let len = request.encode(&mut buf);
+        Request::decode(&buf[..len]).unwrap()
+    }
+
+    fn reply_round_trip(reply: &Reply) -> Reply {
+        let mut buf = [0u8; MAX_MESSAGE];
+        let len = reply.encode(&mut buf);
+        Reply::decode(&buf[..len]).unwrap()
+    }
+
+    #[test]
+    fn request_plain() {
+        let request = Request::new(7, 56, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(request_round_trip(&request), request);
+    }
+
+    #[test]
+    fn request_traceroute() {
+        let mut request = Request::new(u32::MAX, 1400, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        request.probe = Probe::Udp;
+        request.ttl = Some(3);
+        request.port = 33434;
+        request.timeout = 250;
+        assert_eq!(request_round_trip(&request), request);
+    }
+
+    #[test]
+    fn reply_plain() {
+        let reply = Reply::new(1, u32::MAX, 64);
+        assert_eq!(reply_round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn reply_status() {
+        let mut reply = Reply::new(9, 1234, 250);
+        reply.from = Some(IpAddr::V6("fd00::1".parse().unwrap()));
+        reply.status = Status::Unreachable;
+        reply.code = 3;
+        assert_eq!(reply_round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut request = Request::new(1, 56, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        request.ttl = Some(3);
+        let mut buf = [0u8; MAX_MESSAGE];
+        let len = request.encode(&mut buf);
+        // every cut but the one between two options is refused
+        let ends: Vec<usize> = (0..len)
+            .filter(|&cut| Request::decode(&buf[..cut]).is_ok())
+            .collect();
+        assert_eq!(ends, vec![11, 15]);
+
+        let mut reply = Reply::new(1, 2, 3);
+        reply.from = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let len = reply.encode(&mut buf);
+        assert!(matches!(
+            Reply::decode(&buf[..len - 1]),
+            Err(ProtoError::Truncated)
+        ));
+        assert!(matches!(
+            Reply::decode(&buf[..8]),
+            Err(ProtoError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn unknown_option_skipped() {
+        let request = Request::new(1, 56, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let mut buf = [0u8; MAX_MESSAGE];
+        let len = request.encode(&mut buf);
+        buf[len..len + 5].copy_from_slice(&[200, 0, 2, 0xff, 0xff]);
+        assert_eq!(Request::decode(&buf[..len + 5]).unwrap(), request);
+
+        let reply = Reply::new(1, 2, 3);
+        let len = reply.encode(&mut buf);
+        buf[len..len + 3].copy_from_slice(&[200, 0, 0]);
+        assert_eq!(Reply::decode(&buf[..len + 3]).unwrap(), reply);
+    }
+
+    #[test]
+    fn unknown_value_refused() {
+        let request = Request::new(1, 56, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let mut buf = [0u8; MAX_MESSAGE];
+        let len = request.encode(&mut buf);
+
+        buf[len..len + 4].copy_from_slice(&[REQ_PROBE, 0, 1, 7]);
+        assert!(matches!(
+            Request::decode(&buf[..len + 4]),
+            Err(ProtoError::Probe)
+        ));
+
+        // a host is 4 or 16 bytes
+        buf[6] = 5;
+        assert!(matches!(
+            Request::decode(&buf[..len]),
+            Err(ProtoError::Host)
+        ));
+    }
+
+    #[test]
+    fn tos() {
+        let mut request = Request::new(1, 56, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        request.tos = Some(0xb8);
+        assert_eq!(request_round_trip(&request), request);
+
+        let mut reply = Reply::new(1, 2, 3);
+        reply.tos = Some(4);
+        assert_eq!(reply_round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn df_and_mtu() {
+        let mut request = Request::new(1, 1472, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        request.df = true;
+        assert_eq!(request_round_trip(&request), request);
+
+        let mut reply = Reply::new(1, 2, 3);
+        reply.status = Status::TooBig;
+        reply.mtu = Some(1280);
+        assert_eq!(reply_round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn clock() {
+        for clock in [Clock::User, Clock::Software, Clock::Hardware] {
+            let mut reply = Reply::new(1, 2, 3);
+            reply.clock = clock;
+            assert_eq!(reply_round_trip(&reply), reply);
+        }
+    }
+
+    #[test]
+    fn payload_modes() {
+        let mut request = Request::new(1, 56, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        for payload in [
+            Payload::Pattern(vec![0xde, 0xad]),
+            Payload::Random(42),
+            Payload::Zeros,
+            Payload::Bytes(vec![1, 2, 3]),
+            Payload::Bytes(Vec::new()),
+        ] {
+            request.payload = payload;
+            assert_eq!(request_round_trip(&request), request);
+        }
+    }
+
+    #[test]
+    fn payload_refused() {
+        let request = Request::new(1, 56, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let mut buf = [0u8; MAX_MESSAGE];
+        let len = request.encode(&mut buf);
+
+        // an unknown mode, an empty pattern and a seed of the wrong size
+        for value in [&[9][..], &[1], &[2, 0, 0, 0]] {
+            buf[len] = REQ_PAYLOAD;
+            buf[len + 1..len + 3].copy_from_slice(&(value.len() as u16).to_be_bytes());
+            buf[len + 3..len + 3 + value.len()].copy_from_slice(value);
+            assert!(matches!(
+                Request::decode(&buf[..len + 3 + value.len()]),
+                Err(ProtoError::Payload)
+            ));
+        }
+    }
+
+    #[test]
+    fn payload_fill() {
+        let mut buf = [0xaau8; 300];
+        Payload::Sequence.fill(&mut buf);
+        assert_eq!(&buf[..3], &[0, 1, 2]);
+        assert_eq!(&buf[255..258], &[255, 0, 1]);
+
+        let mut buf = [0xaau8; 5];
+        Payload::Pattern(vec![1, 2]).fill(&mut buf);
+        assert_eq!(buf, [1, 2, 1, 2, 1]);
+
+        Payload::Zeros.fill(&mut buf);
+        assert_eq!(buf, [0; 5]);
+
+        let mut buf = [0xaau8; 5];
+        Payload::Bytes(vec![7, 8]).fill(&mut buf);
+        assert_eq!(buf, [7, 8, 0, 0, 0]);
+        Payload::Bytes(vec![1, 2, 3, 4, 5, 6]).fill(&mut buf);
+        assert_eq!(buf, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn payload_fill_random() {
+        let (mut a, mut b, mut c) = ([0u8; 13], [0u8; 13], [0u8; 13]);
+        Payload::Random(1).fill(&mut a);
+        Payload::Random(1).fill(&mut b);
+        Payload::Random(2).fill(&mut c);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+
+        // a zero seed does not stick at zero
+        let mut buf = [0u8; 16];
+        Payload::Random(0).fill(&mut buf);
+        assert_ne!(buf, [0; 16]);
+    }
+
+    #[test]
+    fn reply_error() {
+        let mut reply = Reply::new(1, 0, 0);
+        reply.status = Status::Invalid;
+        reply.error = Some("Invalid packet length".to_string());
+        assert_eq!(reply_round_trip(&reply), reply);
+
+        reply.code = INVALID_SEND;
+        reply.error = Some("No buffer space available".to_string());
+        assert_eq!(reply_round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn validate_length() {
+        let v4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert!(Request::new(1, 65507, v4).validate().is_ok());
+        assert!(Request::new(1, 65508, v4).validate().is_err());
+        assert!(Request::new(1, 65527, v6).validate().is_ok());
+        assert!(Request::new(1, 65528, v6).validate().is_err());
+    }
+
+    #[test]
+    fn arp() {
+        let mut request = Request::new(1, 0, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        request.probe = Probe::Arp;
+        request.iface = Some("eth0".to_string());
+        assert_eq!(request_round_trip(&request), request);
+        assert!(request.validate().is_ok());
+
+        let mut reply = Reply::new(1, 2, 0);
+        reply.mac = Some(MacAddr([2, 0, 0, 0, 0, 1]));
+        assert_eq!(reply_round_trip(&reply), reply);
+
+        request.host = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert!(matches!(request.validate(), Err(ProtoError::Probe)));
+    }
+
+    #[test]
+    fn iface_refused() {
+        let request = Request::new(1, 56, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let mut buf = [0u8; MAX_MESSAGE];
+        let len = request.encode(&mut buf);
+
+        let long = "e".repeat(MAX_IFACE + 1);
+        for iface in [&b""[..], b"eth\0", long.as_bytes()] {
+            buf[len] = REQ_IFACE;
+            buf[len + 1..len + 3].copy_from_slice(&(iface.len() as u16).to_be_bytes());
+            buf[len + 3..len + 3 + iface.len()].copy_from_slice(iface);
+            assert!(matches!(
+                Request::decode(&buf[..len + 3 + iface.len()]),
+                Err(ProtoError::Interface)
+            ));
+        }
+    }
+
+    #[test]
+    fn ndp() {
+        let mut request = Request::new(1, 0, IpAddr::V6("fe80::1".parse().unwrap()));
+        request.probe = Probe::Ndp;
+        request.unicast = true;
+        assert_eq!(request_round_trip(&request), request);
+        assert!(request.validate().is_ok());
+
+        let mut reply = Reply::new(1, 2, 255);
+        reply.mac = Some(MacAddr([2, 0, 0, 0, 0, 1]));
+        reply.nd_flags = Some(0x60);
+        assert_eq!(reply_round_trip(&reply), reply);
+
+        request.host = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(matches!(request.validate(), Err(ProtoError::Probe)));
+    }
+
+    #[test]
+    fn mac_from_str() {
+        let mac = MacAddr([0x02, 0, 0xab, 0xcd, 0xef, 0x01]);
+        assert_eq!("02:00:ab:cd:ef:01".parse::<MacAddr>().unwrap(), mac);
+        assert_eq!("2-0-AB-cd-EF-1".parse::<MacAddr>().unwrap(), mac);
+        assert_eq!(mac.to_string(), "02:00:ab:cd:ef:01");
+
+        for bad in [
+            "",
+            "02:00:ab:cd:ef",
+            "02:00:ab:cd:ef:01:02",
+            "02:00:ab:cd:ef:",
+            "002:00:ab:cd:ef:01",
+            "02:00:ab:cd:ef:0g",
+            "02::ab:cd:ef:01",
+        ] {
+            assert!(bad.parse::<MacAddr>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn vlan() {
+        let mut request = Request::new(1, 56, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        request.vlan = Some(Vlan { id: 4094, pcp: 5 });
+        assert_eq!(request_round_trip(&request), request);
+    }
+
+    #[test]
+    fn vlan_tci() {
+        let vlan = Vlan { id: 100, pcp: 5 };
+        assert_eq!(vlan.tci(), 0xa064);
+        assert_eq!(Vlan::from_tci(vlan.tci()), vlan);
+        assert_eq!(Vlan { id: 4094, pcp: 7 }.tci(), 0xeffe);
+        // drop eligible is dropped on the way in
+        assert_eq!(Vlan::from_tci(0x1064), Vlan { id: 100, pcp: 0 });
+    }
+
+    #[test]
+    fn vlan_validate() {
+        let mut request = Request::new(1, 56, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        for (id, pcp, valid) in [
+            (1, 0, true),
+            (4094, 7, true),
+            (0, 0, false),
+            (4095, 0, false),
+        ] {
+            request.vlan = Some(Vlan { id, pcp });
+            assert_eq!(request.validate().is_ok(), valid, "{} {}", id, pcp);
+        }
+    }
+
+    #[test]
+    fn netns() {
+        let mut request = Request::new(1, 56, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        request.netns = Some("blue".to_string());
+        assert_eq!(request_round_trip(&request), request);
+
+        let mut buf = [0u8; MAX_MESSAGE];
+        let len = Request::new(1, 56, IpAddr::V4(Ipv4Addr::LOCALHOST)).encode(&mut buf);
+        buf[len..len + 5].copy_from_slice(&[REQ_NETNS, 0, 2, b'.', b'.']);
+        assert!(matches!(
+            Request::decode(&buf[..len + 5]),
+            Err(ProtoError::Namespace)
+        ));
+    }
+
+    #[test]
+    fn netns_names() {
+        assert!(netns_valid("blue"));
+        assert!(netns_valid("..."));
+        assert!(netns_valid(&"n".repeat(MAX_NETNS)));
+
+        assert!(!netns_valid(""));
+        assert!(!netns_valid("."));
+        assert!(!netns_valid(".."));
+        assert!(!netns_valid("../etc"));
+        assert!(!netns_valid("a/b"));
+        assert!(!netns_valid("a\0b"));
+        assert!(!netns_valid(&"n".repeat(MAX_NETNS + 1)));
+    }
+
+    #[test]
+    fn coap() {
+        let mut request = Request::new(1, 0, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        request.probe = Probe::Coap;
+        request.port = 5683;
+        assert_eq!(request_round_trip(&request), request);
+    }
+
+    #[test]
+    fn probes() {
+        let mut reply = Reply::new(1, 0, 0);
+        reply.probes = vec![Probe::Echo, Probe::Coap, Probe::Capabilities];
+        assert_eq!(reply_round_trip(&reply), reply);
+
+        // types of a newer proxy are left out
+        let mut buf = [0u8; MAX_MESSAGE];
+        let len = Reply::new(1, 0, 0).encode(&mut buf);
+        buf[len..len + 6].copy_from_slice(&[REP_PROBES, 0, 3, 0, 200, 2]);
+        let reply = Reply::decode(&buf[..len + 6]).unwrap();
+        assert_eq!(reply.probes, vec![Probe::Echo, Probe::Tcp]);
+    }
+
+    #[test]
+    fn timestamps() {
+        let mut request = Request::new(1, 0, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        request.probe = Probe::Timestamp;
+        assert_eq!(request_round_trip(&request), request);
+        assert!(request.validate().is_ok());
+        request.host = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert!(matches!(request.validate(), Err(ProtoError::Probe)));
+
+        let mut reply = Reply::new(1, 2, 3);
+        reply.timestamps = Some(Timestamps {
+            originate: 1,
+            receive: 2,
+            transmit: 3,
+            arrival: u32::MAX,
+        });
+        assert_eq!(reply_round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn timestamps_offset() {
+        let stamps = Timestamps {
+            originate: 1000,
+            receive: 1510,
+            transmit: 1511,
+            arrival: 1021,
+        };
+        assert_eq!(stamps.forward(), 510);
+        assert_eq!(stamps.back(), -490);
+        assert_eq!(stamps.offset(), 500.0);
+        assert!(stamps.standard());
+    }
+
+    #[test]
+    fn timestamps_across_midnight() {
+        let day = DAY_MILLIS as u32;
+        // the proxy sends just before midnight, the target is 100 ms ahead
+        let stamps = Timestamps {
+            originate: day - 50,
+            receive: 55,
+            transmit: 56,
+            arrival: day - 38,
+        };
+        assert_eq!(stamps.forward(), 105);
+        assert_eq!(stamps.back(), -94);
+        assert_eq!(stamps.offset(), 99.5);
+
+        assert_eq!(day_diff(5, day - 5), 10);
+        assert_eq!(day_diff(day - 5, 5), -10);
+        assert_eq!(day_diff(7, 7), 0);
+    }
+
+    #[test]
+    fn timestamps_nonstandard() {
+        let stamps = Timestamps {
+            originate: 0,
+            receive: 0x8000_0001,
+            transmit: 2,
+            arrival: 0,
+        };
+        assert!(!stamps.standard());
+    }
+
+    #[test]
+    fn multi() {
+        let mut request = Request::new(1, 56, IpAddr::V4(Ipv4Addr::BROADCAST));
+        request.multi = true;
+        assert_eq!(request_round_trip(&request), request);
+    }
+
+    #[test]
+    fn limits() {
+        let mut reply = Reply::new(1, 0, 0);
+        reply.limits = Some(Limits {
+            rate: 100_000,
+            in_flight: 8,
+        });
+        assert_eq!(reply_round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn duplicate() {
+        let mut reply = Reply::new(1, 2, 3);
+        reply.duplicate = true;
+        assert_eq!(reply_round_trip(&reply), reply);
+    }
+}
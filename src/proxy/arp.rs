@@ -0,0 +1,186 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::time::{timeout, Duration, Instant};
+
+use ping_proxy::proto::{MacAddr, Reply, Request};
+
+use crate::ping::Ping;
+use crate::probe::{ProbeFuture, Prober};
+use crate::sys;
+
+pub const ETH_P_ARP: u16 = 0x0806;
+const ETH_P_IP: u16 = 0x0800;
+const ARPHRD_ETHER: u16 = 1;
+const ARPOP_REQUEST: u16 = 1;
+const ARPOP_REPLY: u16 = 2;
+
+const BROADCAST: [u8; 6] = [0xFF; 6];
+
+///
+/// An ARP probe broadcasts a who-has for the target on the interface it is
+/// on link from and waits for the is-at, devices that drop ICMP still have
+/// to answer it.
+///
+pub struct ArpProbe;
+
+impl Prober for ArpProbe {
+    fn run(&self, ping: Arc<Ping>, _source: SocketAddr, request: Request) -> ProbeFuture {
+        Box::pin(async move { arp_probe(&ping, &request).await })
+    }
+}
+
+async fn arp_probe(ping: &Ping, request: &Request) -> io::Result<Option<Reply>> {
+    let target = match request.host {
+        IpAddr::V4(target) => target,
+        IpAddr::V6(_) => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+    };
+    let (host, name) = (request.host, request.iface.clone());
+    let (iface, packet) = ping
+        .in_netns(move || {
+            let iface = sys::interface_for(&host, name.as_deref())?;
+            let packet = sys::packet_socket(iface.index, ETH_P_ARP)?;
+            Ok((iface, packet))
+        })
+        .await?;
+    let mac = iface.mac.ok_or_else(|| {
+        let msg = format!("{} has no link layer address", iface.name);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+    let sender = iface.ipv4().unwrap_or(Ipv4Addr::UNSPECIFIED);
+
+    let arp = arp_request(&mac, &sender, &target);
+    let start = Instant::now();
+    sys::packet_send(&packet, &arp, iface.index, ETH_P_ARP, BROADCAST).await?;
+
+    let wait = Duration::from_millis(request.timeout as u64);
+    let mut buf = [0u8; 1500];
+    let result = timeout(wait, async {
+        loop {
+            let len = packet.recv(&mut buf).await?;
+            if let Some(mac) = arp_reply(&buf[..len], &target) {
+                return Ok::<_, io::Error>(mac);
+            }
+        }
+    })
+    .await;
+
+    if let Ok(Ok(mac)) = result {
+        let elapse = start.elapsed().as_micros() as u32;
+        let mut reply = Reply::new(request.seq, elapse, 0);
+        reply.from = Some(request.host);
+        reply.mac = Some(MacAddr(mac));
+        return Ok(Some(reply));
+    }
+
+    Ok(None)
+}
+
+//
+// | hardware type(2B) | protocol type(2B) | hardware length(1B) | protocol length(1B) |
+// | operation(2B) | sender mac(6B) | sender ip(4B) | target mac(6B) | target ip(4B) |
+//
+pub fn arp_request(mac: &[u8; 6], sender: &Ipv4Addr, target: &Ipv4Addr) -> [u8; 28] {
+    let mut arp = [0u8; 28];
+    arp[0..2].copy_from_slice(&ARPHRD_ETHER.to_be_bytes());
+    arp[2..4].copy_from_slice(&ETH_P_IP.to_be_bytes());
+    arp[4] = 6;
+    arp[5] = 4;
+    arp[6..8].copy_from_slice(&ARPOP_REQUEST.to_be_bytes());
+    arp[8..14].copy_from_slice(mac);
+    arp[14..18].copy_from_slice(&sender.octets());
+    arp[24..28].copy_from_slice(&target.octets());
+    arp
+}
+
+/// The is-at from `mac` answering a who-has for `addr`, with the mac of
+/// the host that asked.
+pub fn arp_answer(buf: &[u8], addr: &Ipv4Addr, mac: &[u8; 6]) -> Option<([u8; 28], [u8; 6])> {
+    if buf.len() < 28 || buf[4] != 6 || buf[5] != 4 {
+        return None;
+    }
+    let operation = u16::from_be_bytes([buf[6], buf[7]]);
+    if operation != ARPOP_REQUEST || buf[24..28] != addr.octets() {
+        return None;
+    }
+
+    let mut asker = [0u8; 6];
+    asker.copy_from_slice(&buf[8..14]);
+    let mut arp = [0u8; 28];
+    arp[0..6].copy_from_slice(&buf[0..6]);
+    arp[6..8].copy_from_slice(&ARPOP_REPLY.to_be_bytes());
+    arp[8..14].copy_from_slice(mac);
+    arp[14..18].copy_from_slice(&addr.octets());
+    arp[18..28].copy_from_slice(&buf[8..18]);
+    Some((arp, asker))
+}
+
+/// The sender mac of an ARP reply from `target`.
+pub fn arp_reply(buf: &[u8], target: &Ipv4Addr) -> Option<[u8; 6]> {
+    if buf.len() < 28 || buf[4] != 6 || buf[5] != 4 {
+        return None;
+    }
+    let operation = u16::from_be_bytes([buf[6], buf[7]]);
+    if operation != ARPOP_REPLY || buf[14..18] != target.octets() {
+        return None;
+    }
+
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&buf[8..14]);
+    Some(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_reply() {
+        let asker = [2, 0, 0, 0, 0, 1];
+        let sender = Ipv4Addr::new(10, 0, 0, 1);
+        let target = Ipv4Addr::new(10, 0, 0, 2);
+        let request = arp_request(&asker, &sender, &target);
+        assert_eq!(u16::from_be_bytes([request[6], request[7]]), ARPOP_REQUEST);
+        assert_eq!(&request[8..14], &asker);
+        assert_eq!(&request[24..28], &target.octets());
+        // a who-has is no answer
+        assert_eq!(arp_reply(&request, &target), None);
+
+        let mac = [2, 0, 0, 0, 0, 2];
+        let mut reply = request;
+        reply[6..8].copy_from_slice(&ARPOP_REPLY.to_be_bytes());
+        reply[8..14].copy_from_slice(&mac);
+        reply[14..18].copy_from_slice(&target.octets());
+        assert_eq!(arp_reply(&reply, &target), Some(mac));
+        assert_eq!(arp_reply(&reply, &sender), None);
+        assert_eq!(arp_reply(&reply[..27], &target), None);
+    }
+
+    #[test]
+    fn answer() {
+        let asker = [2, 0, 0, 0, 0, 1];
+        let ours = [2, 0, 0, 0, 0, 2];
+        let asker_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let addr = Ipv4Addr::new(10, 0, 0, 2);
+        let request = arp_request(&asker, &asker_ip, &addr);
+
+        let (arp, from) = arp_answer(&request, &addr, &ours).unwrap();
+        assert_eq!(from, asker);
+        assert_eq!(&arp[0..6], &request[0..6]);
+        assert_eq!(&arp[18..24], &asker);
+        assert_eq!(&arp[24..28], &asker_ip.octets());
+        // the asker learns our mac from the answer
+        assert_eq!(arp_reply(&arp, &addr), Some(ours));
+
+        // a who-has for another address, an is-at, truncated or not
+        // Ethernet and IPv4 addresses
+        assert!(arp_answer(&request, &Ipv4Addr::new(10, 0, 0, 3), &ours).is_none());
+        assert!(arp_answer(&arp, &addr, &ours).is_none());
+        assert!(arp_answer(&request[..27], &addr, &ours).is_none());
+        let mut other = request;
+        other[4] = 8;
+        assert!(arp_answer(&other, &addr, &ours).is_none());
+    }
+}
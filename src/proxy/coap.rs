@@ -0,0 +1,120 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout, Duration},
+};
+
+use ping_proxy::proto::{Reply, Request};
+
+use crate::ping::{Ping, ProbeKey};
+use crate::probe::{ProbeFuture, Prober};
+use crate::sys;
+
+pub const DEFAULT_PORT: u16 = 5683;
+
+const COAP_VERSION: u8 = 1;
+const COAP_CON: u8 = 0;
+const COAP_RST: u8 = 3;
+
+///
+/// A CoAP ping is an empty Confirmable message, a CoAP endpoint answers
+/// it with a Reset carrying the same message ID. Like a UDP probe it owns
+/// an ephemeral socket, so ICMP errors quoting it are answered by the ICMP
+/// engine.
+///
+pub struct CoapProbe;
+
+impl Prober for CoapProbe {
+    fn run(&self, ping: Arc<Ping>, source: SocketAddr, request: Request) -> ProbeFuture {
+        Box::pin(async move { coap_probe(&ping, &source, &request).await })
+    }
+}
+
+async fn coap_probe(
+    ping: &Ping,
+    source: &SocketAddr,
+    request: &Request,
+) -> io::Result<Option<Reply>> {
+    let bind = if request.host.is_ipv4() {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+    } else {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+    };
+    let udp = ping
+        .in_netns(move || {
+            let udp = std::net::UdpSocket::bind(bind)?;
+            udp.set_nonblocking(true)?;
+            UdpSocket::from_std(udp)
+        })
+        .await?;
+    let ipv4 = request.host.is_ipv4();
+    if let Some(ttl) = request.ttl {
+        sys::set_hop_limit(&udp, ipv4, ttl)?;
+    }
+    if let Some(tos) = request.tos {
+        sys::set_tos(&udp, ipv4, tos)?;
+    }
+    sys::set_recv_tos(&udp, ipv4)?;
+
+    let port = if request.port == 0 {
+        DEFAULT_PORT
+    } else {
+        request.port
+    };
+    let target = SocketAddr::new(request.host, port);
+    let key = ProbeKey::Udp(udp.local_addr()?.port());
+
+    let message_id = request.seq as u16;
+    let ping_message = coap_empty(COAP_CON, message_id);
+    let claimed = ping.register(key, source, request.seq, request.timeout);
+    if let Err(err) = udp.send_to(&ping_message, target).await {
+        ping.claim(&key);
+        return Err(err);
+    }
+
+    // anything but the reset to our message, a stray datagram or another
+    // endpoint's, is skipped, the ICMP engine answers port unreachable
+    let wait = Duration::from_millis(request.timeout as u64);
+    let mut buf = [0u8; 1500];
+    let reset = timeout(wait, async {
+        loop {
+            let recv = sys::recv_from(&udp, &mut buf).await?;
+            if recv.from.ip() == request.host && coap_reset(&buf[..recv.len], message_id) {
+                return Ok::<_, io::Error>(recv);
+            }
+        }
+    });
+    let result = tokio::select! {
+        result = reset => result,
+        _ = claimed.notified() => return Ok(None),
+    };
+
+    let pending = ping.claim(&key);
+    if let (Ok(Ok(recv)), Some(pending)) = (result, pending) {
+        let now = ping.elapsed().as_micros() as u64;
+        let mut reply = Reply::new(pending.seq, (now - pending.tx_time) as u32, 0);
+        reply.from = Some(recv.from.ip());
+        reply.tos = recv.tos;
+        return Ok(Some(reply));
+    }
+
+    Ok(None)
+}
+
+/// | ver(2b) | type(2b) | token length(4b) | code(1B) | message id(2B) |,
+/// an empty message has code 0.00 and no token, options or payload.
+fn coap_empty(kind: u8, message_id: u16) -> [u8; 4] {
+    let id = message_id.to_be_bytes();
+    [COAP_VERSION << 6 | kind << 4, 0, id[0], id[1]]
+}
+
+fn coap_reset(buf: &[u8], message_id: u16) -> bool {
+    buf.len() >= 4
+        && buf[0] >> 6 == COAP_VERSION
+        && (buf[0] >> 4) & 0x03 == COAP_RST
+        && u16::from_be_bytes([buf[2], buf[3]]) == message_id
+}
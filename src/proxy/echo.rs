@@ -0,0 +1,60 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use ping_proxy::proto::{Reply, Request, Status};
+
+use crate::ping::Ping;
+use crate::probe::{ProbeFuture, Prober};
+use crate::sys;
+
+///
+/// The ICMP echo request, the reply comes in on the engine's sockets and is
+/// answered from there. In raw Ethernet mode the request goes out in a
+/// frame of our own, resolving the next hop may take up to half the probe
+/// timeout.
+///
+pub struct EchoProbe;
+
+impl Prober for EchoProbe {
+    fn check(&self, ping: &Ping, request: &Request) -> Result<(), String> {
+        if request.vlan.is_some() && !ping.ether_enabled() {
+            return Err("VLAN tags need the proxy in raw Ethernet mode".to_string());
+        }
+        if let IpAddr::V6(group) = request.host {
+            // the kernel cannot tell which link ff02::1 is on
+            let link_scope = group.segments()[0] & 0xff0f == 0xff02;
+            if link_scope && request.iface.is_none() && !ping.ether_enabled() {
+                return Err(format!("{} needs an interface, use -I", group));
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&self, ping: Arc<Ping>, source: SocketAddr, request: Request) -> ProbeFuture {
+        Box::pin(async move {
+            match ping.send_to(&source, &request).await {
+                Err(err) if request.df && err.raw_os_error() == Some(libc::EMSGSIZE) => {
+                    Ok(Some(too_big(&ping, &request).await))
+                }
+                Err(err) => Err(err),
+                Ok(_) => Ok(None),
+            }
+        })
+    }
+}
+
+/// Answers a don't fragment probe too big for the local link as the first
+/// hop would.
+async fn too_big(ping: &Ping, request: &Request) -> Reply {
+    let mut reply = Reply::new(request.seq, 0, 0);
+    reply.status = Status::TooBig;
+    let host = request.host;
+    reply.mtu = ping
+        .in_netns(move || sys::path_mtu(host))
+        .await
+        .ok()
+        .map(|mtu| mtu.min(u16::MAX as u32) as u16);
+    reply
+}
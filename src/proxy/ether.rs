@@ -0,0 +1,637 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Mutex,
+    },
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout, Duration, Instant},
+};
+
+use ping_proxy::proto::{MacAddr, Request, Vlan};
+
+use crate::ping::ip_checksum;
+use crate::sys::{self, Interface, RecvInfo};
+use crate::{arp, ndp};
+
+const ETH_P_ALL: u16 = 0x0003;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86DD;
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_HEADER: usize = 14;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_ICMPV6: u8 = 58;
+const DEFAULT_HOP_LIMIT: u8 = 64;
+
+const BROADCAST: [u8; 6] = [0xFF; 6];
+
+/// How long a resolved next hop is used before it is asked for again, the
+/// solicitation also keeps the configured MAC in the neighbour's cache.
+const NEIGHBOUR_LIFETIME: Duration = Duration::from_secs(30);
+
+/// The raw Ethernet mode as given on the command line.
+#[derive(Debug, Clone)]
+pub struct EtherConfig {
+    pub iface: String,
+    /// source MAC of every frame, the interface's own when not set
+    pub mac: Option<MacAddr>,
+    /// next hop for every frame, resolved per target when not set
+    pub gateway: Option<MacAddr>,
+    pub vlans: Vec<VlanAddr>,
+}
+
+/// An address of the proxy on a VLAN of the trunk, with the gateway off
+/// link targets on that VLAN go through.
+#[derive(Debug, Clone)]
+pub struct VlanAddr {
+    pub id: u16,
+    pub addr: IpAddr,
+    pub prefix: u8,
+    pub gateway: Option<IpAddr>,
+}
+
+/// The addresses and gateways of one VLAN, the kernel knows nothing of
+/// them so the proxy answers ARP and neighbor solicitations for them.
+#[derive(Debug)]
+struct VlanNet {
+    link: Interface,
+    gateways: Vec<IpAddr>,
+}
+
+/// A resolved next hop and when it was resolved.
+type Neighbour = ([u8; 6], Instant);
+
+/// An IP packet taken out of a frame to our MAC.
+#[derive(Debug)]
+pub struct Packet {
+    /// the whole IP packet in the receive buffer
+    pub range: Range<usize>,
+    /// offset of the ICMP header in the packet
+    pub icmp_offset: usize,
+    pub v6: bool,
+    pub recv: RecvInfo,
+}
+
+///
+/// Echo requests go out as whole frames from the configured MAC on an
+/// AF_PACKET socket, so devices that only answer a whitelisted MAC can be
+/// probed from any box. The next hop is resolved with ARP or neighbor
+/// solicitations sent from that MAC as well, and replies to it are taken
+/// off the same socket. Frames are never fragmented, probes larger than
+/// the link MTU fail.
+///
+/// Requests with a VLAN go out 802.1Q tagged on the trunk, from the
+/// addresses configured for that VLAN or the interface's own when there
+/// are none.
+///
+#[derive(Debug)]
+pub struct Ether {
+    iface: Interface,
+    mac: [u8; 6],
+    gateway: Option<[u8; 6]>,
+    vlans: HashMap<u16, VlanNet>,
+    socket: UdpSocket,
+    // keyed by VLAN id, 0 untagged, and next hop
+    neighbours: Mutex<HashMap<(u16, IpAddr), Neighbour>>,
+    ip_id: AtomicU16,
+}
+
+impl Ether {
+    pub fn new(config: &EtherConfig) -> io::Result<Ether> {
+        let iface = sys::interfaces()?
+            .into_iter()
+            .find(|iface| iface.name == config.iface)
+            .ok_or_else(|| {
+                let msg = format!("no interface {}", config.iface);
+                io::Error::new(io::ErrorKind::NotFound, msg)
+            })?;
+        let mac = match (config.mac, iface.mac) {
+            (Some(mac), _) => mac.0,
+            (None, Some(mac)) => mac,
+            (None, None) => {
+                let msg = format!("{} has no link layer address, use -M", iface.name);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+
+        let socket = sys::raw_packet_socket(iface.index, ETH_P_ALL)?;
+        sys::set_packet_auxdata(&socket)?;
+        if iface.mac != Some(mac) {
+            sys::add_unicast_mac(&socket, iface.index, &mac)?;
+        }
+
+        let mut vlans: HashMap<u16, VlanNet> = HashMap::new();
+        for vlan in &config.vlans {
+            let net = vlans.entry(vlan.id).or_insert_with(|| VlanNet {
+                link: Interface {
+                    name: format!("{}.{}", iface.name, vlan.id),
+                    index: iface.index,
+                    mac: Some(mac),
+                    addrs: Vec::new(),
+                },
+                gateways: Vec::new(),
+            });
+            net.link.addrs.push((vlan.addr, vlan.prefix));
+            net.gateways.extend(vlan.gateway);
+            if let IpAddr::V6(addr) = vlan.addr {
+                let group = multicast_mac(&ndp::solicited_node(&addr));
+                sys::add_multicast_mac(&socket, iface.index, &group)?;
+            }
+        }
+
+        Ok(Ether {
+            iface,
+            mac,
+            gateway: config.gateway.map(|mac| mac.0),
+            vlans,
+            socket,
+            neighbours: Mutex::new(HashMap::new()),
+            ip_id: AtomicU16::new(sys::random_u16()),
+        })
+    }
+
+    /// Sends the ICMP message `icmp` to the request's host, the IP header
+    /// is built from the request's TTL, TOS and DF. Resolving the next hop
+    /// takes at most half the probe timeout, so a failure still reaches the
+    /// client before it gives up.
+    pub async fn send(&self, icmp: &[u8], request: &Request) -> io::Result<usize> {
+        let wait = Duration::from_millis(request.timeout as u64 / 2);
+        let vlan = request.vlan;
+        let dest = match request.host {
+            IpAddr::V6(group) if group.is_multicast() => multicast_mac(&group),
+            IpAddr::V4(group) if group.is_multicast() => ipv4_multicast_mac(&group),
+            IpAddr::V4(target) if self.link(vlan).broadcast(&target) => BROADCAST,
+            _ => self.next_hop(vlan, &request.host, wait).await?,
+        };
+        let hop_limit = request.ttl.unwrap_or(DEFAULT_HOP_LIMIT);
+        let (ethertype, packet) = match request.host {
+            IpAddr::V4(target) => {
+                let source = self.ipv4_source(vlan)?;
+                let packet =
+                    self.ipv4_packet(&source, &target, hop_limit, request.tos, request.df, icmp)?;
+                (ETH_P_IP, packet)
+            }
+            IpAddr::V6(target) => {
+                let source = self.ipv6_source(vlan, &target)?;
+                let packet = ipv6_packet(&source, &target, hop_limit, request.tos, icmp)?;
+                (ETH_P_IPV6, packet)
+            }
+        };
+        let frame = self.frame(dest, vlan, ethertype, &packet);
+        self.socket.send(&frame).await
+    }
+
+    /// Waits for an ICMP packet in a frame to our MAC, answering the ARP
+    /// and neighbor solicitations for our VLAN addresses on the way.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<Packet> {
+        loop {
+            let (len, vlan) = read_frame(&self.socket, buf).await?;
+            let frame = &mut buf[..len];
+            if let Some(vlan) = vlan {
+                if let Some(net) = self.vlans.get(&vlan.id) {
+                    self.answer(vlan, net, frame).await;
+                }
+            }
+            if let Some(packet) = self.packet(frame) {
+                return Ok(packet);
+            }
+        }
+    }
+
+    fn packet(&self, frame: &mut [u8]) -> Option<Packet> {
+        if frame.len() < ETH_HEADER || frame[0..6] != self.mac {
+            return None;
+        }
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        let ip = &mut frame[ETH_HEADER..];
+
+        // the frame may be padded, the IP header has the real length
+        let (len, icmp_offset, from, hop_limit, tos) = match ethertype {
+            ETH_P_IP => {
+                if ip.len() < 20 || ip[0] >> 4 != 4 || ip[9] != IPPROTO_ICMP {
+                    return None;
+                }
+                let ihl = ((ip[0] & 0xF) * 4) as usize;
+                let len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+                if ihl < 20 || len < ihl || len > ip.len() {
+                    return None;
+                }
+                if ip_checksum(&mut ip[..ihl]) != 0 || ip_checksum(&mut ip[ihl..len]) != 0 {
+                    return None;
+                }
+                let from = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+                (len, ihl, IpAddr::V4(from), ip[8], ip[1])
+            }
+            ETH_P_IPV6 => {
+                if ip.len() < 40 || ip[0] >> 4 != 6 || ip[6] != IPPROTO_ICMPV6 {
+                    return None;
+                }
+                let len = 40 + u16::from_be_bytes([ip[4], ip[5]]) as usize;
+                if len > ip.len() {
+                    return None;
+                }
+                let mut source = [0u8; 16];
+                source.copy_from_slice(&ip[8..24]);
+                let mut dest = [0u8; 16];
+                dest.copy_from_slice(&ip[24..40]);
+                let from = Ipv6Addr::from(source);
+                if icmp6_checksum(&from, &Ipv6Addr::from(dest), &ip[40..len]) != 0 {
+                    return None;
+                }
+                let tos = (ip[0] << 4) | (ip[1] >> 4);
+                (len, 40, IpAddr::V6(from), ip[7], tos)
+            }
+            _ => return None,
+        };
+
+        Some(Packet {
+            range: ETH_HEADER..ETH_HEADER + len,
+            icmp_offset,
+            v6: ethertype == ETH_P_IPV6,
+            recv: RecvInfo {
+                len,
+                from: SocketAddr::new(from, 0),
+                hop_limit: Some(hop_limit),
+                tos: Some(tos),
+                stamp: None,
+                tx_key: None,
+            },
+        })
+    }
+
+    async fn answer(&self, vlan: Vlan, net: &VlanNet, frame: &[u8]) {
+        if frame.len() < ETH_HEADER || (frame[0..6] != self.mac && frame[0] & 1 == 0) {
+            return;
+        }
+
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        let reply = match ethertype {
+            arp::ETH_P_ARP => net
+                .link
+                .addrs
+                .iter()
+                .find_map(|(addr, _)| match addr {
+                    IpAddr::V4(addr) => arp::arp_answer(&frame[ETH_HEADER..], addr, &self.mac),
+                    IpAddr::V6(_) => None,
+                })
+                .map(|(arp, asker)| self.frame(asker, Some(vlan), arp::ETH_P_ARP, &arp)),
+            ETH_P_IPV6 => self.ndp_answer(vlan, net, frame),
+            _ => None,
+        };
+
+        if let Some(reply) = reply {
+            if let Err(err) = self.socket.send(&reply).await {
+                println!("vlan {} neighbour answer error: {}", vlan.id, err);
+            }
+        }
+    }
+
+    // duplicate address detection solicitations come from the unspecified
+    // address and are left alone
+    fn ndp_answer(&self, vlan: Vlan, net: &VlanNet, frame: &[u8]) -> Option<Vec<u8>> {
+        let packet = &frame[ETH_HEADER..];
+        if packet.len() < 40 || packet[6] != IPPROTO_ICMPV6 || packet[7] != ndp::ND_HOP_LIMIT {
+            return None;
+        }
+        let len = 40 + u16::from_be_bytes([packet[4], packet[5]]) as usize;
+        if len > packet.len() {
+            return None;
+        }
+        let mut source = [0u8; 16];
+        source.copy_from_slice(&packet[8..24]);
+        let source = Ipv6Addr::from(source);
+        if source.is_unspecified() {
+            return None;
+        }
+
+        let (advert, asker, addr) = net.link.addrs.iter().find_map(|(addr, _)| match addr {
+            IpAddr::V6(addr) => ndp::neighbor_answer(&packet[40..len], addr, &self.mac)
+                .map(|(advert, asker)| (advert, asker, *addr)),
+            IpAddr::V4(_) => None,
+        })?;
+        let mut dest = [0u8; 6];
+        dest.copy_from_slice(&frame[6..12]);
+        let dest = asker.unwrap_or(dest);
+        let packet = ipv6_packet(&addr, &source, ndp::ND_HOP_LIMIT, None, &advert).ok()?;
+        Some(self.frame(dest, Some(vlan), ETH_P_IPV6, &packet))
+    }
+
+    //
+    // | version(4b) | ihl(4b) | tos(1B) | total length(2B) | id(2B) | flags(3b) |
+    // | fragment offset(13b) | ttl(1B) | protocol(1B) | checksum(2B) | source(4B) |
+    // | destination(4B) |
+    //
+    fn ipv4_packet(
+        &self,
+        source: &Ipv4Addr,
+        target: &Ipv4Addr,
+        ttl: u8,
+        tos: Option<u8>,
+        df: bool,
+        icmp: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let len = 20 + icmp.len();
+        if len > u16::MAX as usize {
+            return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+        }
+
+        let mut ip = vec![0u8; len];
+        ip[0] = 0x45;
+        ip[1] = tos.unwrap_or(0);
+        ip[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        let id = self.ip_id.fetch_add(1, Ordering::Relaxed);
+        ip[4..6].copy_from_slice(&id.to_be_bytes());
+        if df {
+            ip[6] = 0x40;
+        }
+        ip[8] = ttl;
+        ip[9] = IPPROTO_ICMP;
+        ip[12..16].copy_from_slice(&source.octets());
+        ip[16..20].copy_from_slice(&target.octets());
+        let checksum = ip_checksum(&mut ip[..20]);
+        ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+        ip[20..].copy_from_slice(icmp);
+
+        Ok(ip)
+    }
+
+    /// An Ethernet frame from our MAC, 802.1Q tagged when there is a VLAN.
+    fn frame(&self, dest: [u8; 6], vlan: Option<Vlan>, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(ETH_HEADER + 4 + payload.len());
+        frame.extend_from_slice(&dest);
+        frame.extend_from_slice(&self.mac);
+        if let Some(vlan) = vlan {
+            frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+            frame.extend_from_slice(&vlan.tci().to_be_bytes());
+        }
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// The interface itself, or the VLAN with its configured addresses.
+    fn link(&self, vlan: Option<Vlan>) -> &Interface {
+        match vlan.and_then(|vlan| self.vlans.get(&vlan.id)) {
+            Some(net) => &net.link,
+            None => &self.iface,
+        }
+    }
+
+    fn ipv4_source(&self, vlan: Option<Vlan>) -> io::Result<Ipv4Addr> {
+        let link = self.link(vlan);
+        link.ipv4().ok_or_else(|| no_address(link, "IPv4"))
+    }
+
+    /// A link local source for link local targets, a global one otherwise,
+    /// whatever there is when the link lacks that kind.
+    fn ipv6_source(&self, vlan: Option<Vlan>, target: &Ipv6Addr) -> io::Result<Ipv6Addr> {
+        let link = self.link(vlan);
+        let addrs: Vec<Ipv6Addr> = link
+            .addrs
+            .iter()
+            .filter_map(|(addr, _)| match addr {
+                IpAddr::V6(addr) => Some(*addr),
+                IpAddr::V4(_) => None,
+            })
+            .collect();
+        let link_local = is_link_local(target) || is_link_scope(target);
+        addrs
+            .iter()
+            .find(|addr| is_link_local(addr) == link_local)
+            .or_else(|| addrs.first())
+            .copied()
+            .ok_or_else(|| no_address(link, "IPv6"))
+    }
+
+    /// The address frames to `target` go to, the target itself when it is
+    /// on link, the gateway of the VLAN or of the route through the
+    /// interface otherwise.
+    fn route(&self, vlan: Option<Vlan>, target: &IpAddr) -> io::Result<IpAddr> {
+        if let IpAddr::V6(addr) = target {
+            if is_link_local(addr) {
+                return Ok(*target);
+            }
+        }
+
+        let hop = match vlan.and_then(|vlan| self.vlans.get(&vlan.id)) {
+            Some(net) if net.link.on_link(target) => Some(*target),
+            Some(net) => net
+                .gateways
+                .iter()
+                .find(|gateway| gateway.is_ipv4() == target.is_ipv4())
+                .copied(),
+            None => sys::route_next_hop(&self.iface.name, target)?,
+        };
+        hop.ok_or_else(|| {
+            let msg = format!("no route to {} through {}", target, self.link(vlan).name);
+            io::Error::new(io::ErrorKind::NotFound, msg)
+        })
+    }
+
+    /// The MAC frames to `target` go to.
+    async fn next_hop(
+        &self,
+        vlan: Option<Vlan>,
+        target: &IpAddr,
+        wait: Duration,
+    ) -> io::Result<[u8; 6]> {
+        if let Some(gateway) = self.gateway {
+            return Ok(gateway);
+        }
+
+        let hop = self.route(vlan, target)?;
+        let key = (vlan.map_or(0, |vlan| vlan.id), hop);
+        if let Some((mac, resolved)) = self.neighbours.lock().unwrap().get(&key) {
+            if resolved.elapsed() < NEIGHBOUR_LIFETIME {
+                return Ok(*mac);
+            }
+        }
+
+        let mac = match hop {
+            IpAddr::V4(hop) => self.arp_resolve(vlan, &hop, wait).await?,
+            IpAddr::V6(hop) => self.ndp_resolve(vlan, &hop, wait).await?,
+        };
+        let mac = mac.ok_or_else(|| {
+            let msg = format!("no link layer address for {}", hop);
+            io::Error::new(io::ErrorKind::HostUnreachable, msg)
+        })?;
+        self.neighbours
+            .lock()
+            .unwrap()
+            .insert(key, (mac, Instant::now()));
+
+        Ok(mac)
+    }
+
+    async fn arp_resolve(
+        &self,
+        vlan: Option<Vlan>,
+        hop: &Ipv4Addr,
+        wait: Duration,
+    ) -> io::Result<Option<[u8; 6]>> {
+        let sender = self.ipv4_source(vlan)?;
+        let arp = arp::arp_request(&self.mac, &sender, hop);
+        let frame = self.frame(BROADCAST, vlan, arp::ETH_P_ARP, &arp);
+
+        self.exchange(vlan, arp::ETH_P_ARP, &frame, wait, |reply| {
+            arp::arp_reply(reply, hop)
+        })
+        .await
+    }
+
+    async fn ndp_resolve(
+        &self,
+        vlan: Option<Vlan>,
+        hop: &Ipv6Addr,
+        wait: Duration,
+    ) -> io::Result<Option<[u8; 6]>> {
+        let source = self.ipv6_source(vlan, hop)?;
+        let group = ndp::solicited_node(hop);
+        let solicit = ndp::neighbor_solicit(hop, &self.mac);
+        let packet = ipv6_packet(&source, &group, ndp::ND_HOP_LIMIT, None, &solicit)?;
+        let frame = self.frame(multicast_mac(&group), vlan, ETH_P_IPV6, &packet);
+
+        self.exchange(vlan, ETH_P_IPV6, &frame, wait, |packet| {
+            if packet.len() < 40 || packet[6] != IPPROTO_ICMPV6 || packet[7] != ndp::ND_HOP_LIMIT {
+                return None;
+            }
+            ndp::neighbor_advert(&packet[40..], hop).map(|(_, mac)| mac)
+        })
+        .await
+        .map(|mac| mac.flatten())
+    }
+
+    /// Sends `frame` and waits for a frame to our MAC on the same VLAN
+    /// `matches` takes an answer out of, it gets the frame without the
+    /// Ethernet header.
+    async fn exchange<T, F>(
+        &self,
+        vlan: Option<Vlan>,
+        protocol: u16,
+        frame: &[u8],
+        wait: Duration,
+        matches: F,
+    ) -> io::Result<Option<T>>
+    where
+        F: Fn(&[u8]) -> Option<T>,
+    {
+        let socket = sys::raw_packet_socket(self.iface.index, ETH_P_ALL)?;
+        sys::set_packet_auxdata(&socket)?;
+        socket.send(frame).await?;
+
+        let vlan_id = vlan.map(|vlan| vlan.id);
+        let mut buf = [0u8; 2048];
+        let result = timeout(wait, async {
+            loop {
+                let (len, tag) = read_frame(&socket, &mut buf).await?;
+                let frame = &buf[..len];
+                if len < ETH_HEADER
+                    || frame[0..6] != self.mac
+                    || frame[12..14] != protocol.to_be_bytes()
+                    || tag.map(|tag| tag.id) != vlan_id
+                {
+                    continue;
+                }
+                if let Some(answer) = matches(&frame[ETH_HEADER..]) {
+                    return Ok::<_, io::Error>(answer);
+                }
+            }
+        })
+        .await;
+
+        match result {
+            Ok(Ok(answer)) => Ok(Some(answer)),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Reads a frame with its 802.1Q tag taken out, whether the driver
+/// stripped it already or it is still in the frame. Priority tagged frames
+/// count as untagged.
+async fn read_frame(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Option<Vlan>)> {
+    let (mut len, mut tci) = sys::packet_recv(socket, buf).await?;
+    if tci.is_none() && len >= ETH_HEADER + 4 && buf[12..14] == ETH_P_8021Q.to_be_bytes() {
+        tci = Some(u16::from_be_bytes([buf[14], buf[15]]));
+        buf.copy_within(16..len, 12);
+        len -= 4;
+    }
+    let vlan = tci.map(Vlan::from_tci).filter(|vlan| vlan.id != 0);
+    Ok((len, vlan))
+}
+
+fn no_address(link: &Interface, family: &str) -> io::Error {
+    let msg = format!("{} has no {} address", link.name, family);
+    io::Error::new(io::ErrorKind::AddrNotAvailable, msg)
+}
+
+//
+// | version(4b) | traffic class(1B) | flow label(20b) | payload length(2B) |
+// | next header(1B) | hop limit(1B) | source(16B) | destination(16B) |
+//
+fn ipv6_packet(
+    source: &Ipv6Addr,
+    target: &Ipv6Addr,
+    hop_limit: u8,
+    tos: Option<u8>,
+    icmp: &[u8],
+) -> io::Result<Vec<u8>> {
+    if icmp.len() > u16::MAX as usize {
+        return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+    }
+
+    let mut ip = vec![0u8; 40 + icmp.len()];
+    let tos = tos.unwrap_or(0);
+    ip[0] = 0x60 | (tos >> 4);
+    ip[1] = tos << 4;
+    ip[4..6].copy_from_slice(&(icmp.len() as u16).to_be_bytes());
+    ip[6] = IPPROTO_ICMPV6;
+    ip[7] = hop_limit;
+    ip[8..24].copy_from_slice(&source.octets());
+    ip[24..40].copy_from_slice(&target.octets());
+    ip[40..].copy_from_slice(icmp);
+    ip[42..44].copy_from_slice(&[0, 0]);
+    let checksum = icmp6_checksum(source, target, &ip[40..]);
+    ip[42..44].copy_from_slice(&checksum.to_be_bytes());
+
+    Ok(ip)
+}
+
+/// 33:33 and the low 32 bits of an IPv6 multicast group.
+fn multicast_mac(group: &Ipv6Addr) -> [u8; 6] {
+    let octets = group.octets();
+    [0x33, 0x33, octets[12], octets[13], octets[14], octets[15]]
+}
+
+/// 01:00:5e and the low 23 bits of the group.
+fn ipv4_multicast_mac(group: &Ipv4Addr) -> [u8; 6] {
+    let octets = group.octets();
+    [0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3]]
+}
+
+fn is_link_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// A link scope multicast group like ff02::1.
+fn is_link_scope(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xff0f == 0xff02
+}
+
+/// The ICMPv6 checksum, it covers a pseudo header of the addresses, the
+/// length and the next header.
+fn icmp6_checksum(source: &Ipv6Addr, dest: &Ipv6Addr, icmp: &[u8]) -> u16 {
+    let mut buf = Vec::with_capacity(40 + icmp.len());
+    buf.extend_from_slice(&source.octets());
+    buf.extend_from_slice(&dest.octets());
+    buf.extend_from_slice(&(icmp.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, IPPROTO_ICMPV6]);
+    buf.extend_from_slice(icmp);
+    ip_checksum(&mut buf)
+}
@@ -0,0 +1,119 @@
+use std::{collections::HashMap, net::SocketAddr, time::Instant};
+
+use ping_proxy::proto::Limits;
+
+pub const DEFAULT_RATE: u32 = 1000;
+pub const DEFAULT_IN_FLIGHT: u16 = 1024;
+
+/// Clients quiet for this long lose their bucket.
+const IDLE_SECS: u64 = 60;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+///
+/// Keeps every client within the proxy's limits, a token bucket refilled
+/// at `rate` probes a second holding at most a second's worth, and a cap
+/// on the probes waiting for an answer.
+///
+#[derive(Debug)]
+pub struct Limiter {
+    limits: Limits,
+    clients: HashMap<SocketAddr, Bucket>,
+    last_sweep: Instant,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Limiter {
+            limits,
+            clients: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Takes a probe of `client` with `in_flight` probes waiting for an
+    /// answer in all engines, or tells which limit it is over.
+    pub fn admit(&mut self, client: SocketAddr, in_flight: usize) -> Result<(), String> {
+        let now = Instant::now();
+        self.sweep(now);
+
+        let rate = self.limits.rate as f64;
+        let bucket = self.clients.entry(client).or_insert(Bucket {
+            tokens: rate,
+            last: now,
+        });
+        let refill = now.duration_since(bucket.last).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate);
+        bucket.last = now;
+        if bucket.tokens < 1.0 {
+            return Err(format!("over {} probes a second", self.limits.rate));
+        }
+
+        if in_flight >= self.limits.in_flight as usize {
+            return Err(format!("over {} probes in flight", self.limits.in_flight));
+        }
+
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep).as_secs() < IDLE_SECS {
+            return;
+        }
+        self.clients
+            .retain(|_, bucket| now.duration_since(bucket.last).as_secs() < IDLE_SECS);
+        self.last_sweep = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn rate() {
+        let client = "127.0.0.1:5000".parse().unwrap();
+        let mut limiter = Limiter::new(Limits {
+            rate: 10,
+            in_flight: 100,
+        });
+        // a second's worth at once, then the bucket is empty
+        for _ in 0..10 {
+            assert!(limiter.admit(client, 0).is_ok());
+        }
+        assert!(limiter.admit(client, 0).is_err());
+
+        // another client has a bucket of its own
+        let other = "127.0.0.1:5001".parse().unwrap();
+        assert!(limiter.admit(other, 0).is_ok());
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(limiter.admit(client, 0).is_ok());
+    }
+
+    #[test]
+    fn in_flight() {
+        let client = "127.0.0.1:5000".parse().unwrap();
+        let mut limiter = Limiter::new(Limits {
+            rate: 10,
+            in_flight: 2,
+        });
+        assert!(limiter.admit(client, 1).is_ok());
+        assert!(limiter.admit(client, 2).is_err());
+        // a probe refused over the cap spends no token
+        for _ in 0..9 {
+            assert!(limiter.admit(client, 0).is_ok());
+        }
+        assert!(limiter.admit(client, 0).is_err());
+    }
+}
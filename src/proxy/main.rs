@@ -1,15 +1,44 @@
+mod arp;
+mod coap;
+mod echo;
+mod ether;
+mod limit;
+mod ndp;
 mod ping;
+mod probe;
 mod proxy;
+mod sys;
+mod tcp;
+mod timestamp;
+mod udp;
+
+use std::net::IpAddr;
+
+use ping_proxy::proto::{netns_valid, Limits, MacAddr, Vlan, MAX_IFACE};
+
+use crate::ether::{EtherConfig, VlanAddr};
 
 #[derive(Debug)]
 struct CliArgs {
     port: u16,
+    iface: Option<String>,
+    mac: Option<MacAddr>,
+    gateway: Option<MacAddr>,
+    vlans: Vec<VlanAddr>,
+    netns: Vec<String>,
+    limits: Limits,
 }
 
 #[tokio::main]
 async fn main() {
     let args = cli_parse();
-    if let Err(err) = proxy::server("0.0.0.0", args.port).await {
+    let ether = args.iface.map(|iface| EtherConfig {
+        iface,
+        mac: args.mac,
+        gateway: args.gateway,
+        vlans: args.vlans,
+    });
+    if let Err(err) = proxy::server("0.0.0.0", args.port, ether, &args.netns, args.limits).await {
         println!("proxy run error: {}", err);
         std::process::exit(1);
     }
@@ -17,13 +46,31 @@ async fn main() {
 
 impl CliArgs {
     pub fn new() -> Self {
-        CliArgs { port: 2000 }
+        CliArgs {
+            port: 2000,
+            iface: None,
+            mac: None,
+            gateway: None,
+            vlans: Vec::new(),
+            netns: Vec::new(),
+            limits: Limits {
+                rate: limit::DEFAULT_RATE,
+                in_flight: limit::DEFAULT_IN_FLIGHT,
+            },
+        }
     }
 }
 
 fn usage() {
     println!("Usage: proxy [options]");
     println!("  -p    listen port, default 2000");
+    println!("  -i    send echo requests as raw Ethernet frames on the interface");
+    println!("  -M    source MAC of the frames, the interface's by default");
+    println!("  -g    next hop MAC of the frames, resolved by default");
+    println!("  -V    ID,ADDR/PREFIX[,GATEWAY] proxy address on a VLAN of the trunk");
+    println!("  -n    network namespace under /var/run/netns to probe from, repeatable");
+    println!("  -r    probes a second one client may send, default 1000");
+    println!("  -l    probes one client may have in flight, default 1024");
     println!("  -v    version");
     println!("  -h    help");
 }
@@ -57,6 +104,100 @@ fn cli_parse() -> CliArgs {
                 }
             }
 
+            "-i" => {
+                if let Some(value) = iter.next() {
+                    if !value.is_empty() && value.len() <= MAX_IFACE {
+                        cli_args.iface = Some(value.clone());
+                        continue;
+                    }
+                    println!("invalid interface");
+                    std::process::exit(1);
+                } else {
+                    println!("no interface specified");
+                    std::process::exit(1);
+                }
+            }
+
+            "-M" | "-g" => {
+                if let Some(value) = iter.next() {
+                    if let Ok(mac) = value.parse::<MacAddr>() {
+                        if key == "-M" {
+                            cli_args.mac = Some(mac);
+                        } else {
+                            cli_args.gateway = Some(mac);
+                        }
+                        continue;
+                    }
+                    println!("invalid mac address");
+                    std::process::exit(1);
+                } else {
+                    println!("no mac address specified");
+                    std::process::exit(1);
+                }
+            }
+
+            "-V" => {
+                if let Some(value) = iter.next() {
+                    if let Some(vlan) = vlan_parse(value) {
+                        cli_args.vlans.push(vlan);
+                        continue;
+                    }
+                    println!("invalid vlan address");
+                    std::process::exit(1);
+                } else {
+                    println!("no vlan address specified");
+                    std::process::exit(1);
+                }
+            }
+
+            "-n" => {
+                if let Some(value) = iter.next() {
+                    if netns_valid(value) {
+                        if !cli_args.netns.contains(value) {
+                            cli_args.netns.push(value.clone());
+                        }
+                        continue;
+                    }
+                    println!("invalid namespace");
+                    std::process::exit(1);
+                } else {
+                    println!("no namespace specified");
+                    std::process::exit(1);
+                }
+            }
+
+            "-r" => {
+                if let Some(value) = iter.next() {
+                    if let Ok(rate) = value.parse::<u32>() {
+                        if rate > 0 {
+                            cli_args.limits.rate = rate;
+                            continue;
+                        }
+                    }
+                    println!("invalid rate");
+                    std::process::exit(1);
+                } else {
+                    println!("no rate specified");
+                    std::process::exit(1);
+                }
+            }
+
+            "-l" => {
+                if let Some(value) = iter.next() {
+                    if let Ok(in_flight) = value.parse::<u16>() {
+                        if in_flight > 0 {
+                            cli_args.limits.in_flight = in_flight;
+                            continue;
+                        }
+                    }
+                    println!("invalid in flight limit");
+                    std::process::exit(1);
+                } else {
+                    println!("no in flight limit specified");
+                    std::process::exit(1);
+                }
+            }
+
             "-v" => {
                 println!("version 0.1.0");
                 std::process::exit(0);
@@ -74,5 +215,38 @@ fn cli_parse() -> CliArgs {
         }
     }
 
+    let ether_only = cli_args.mac.is_some() || cli_args.gateway.is_some();
+    if cli_args.iface.is_none() && (ether_only || !cli_args.vlans.is_empty()) {
+        println!("-M, -g and -V need raw Ethernet mode, use -i");
+        std::process::exit(1);
+    }
+
     cli_args
 }
+
+/// ID,ADDR/PREFIX[,GATEWAY]
+fn vlan_parse(value: &str) -> Option<VlanAddr> {
+    let mut fields = value.split(',');
+    let id = fields.next()?.parse::<u16>().ok()?;
+    let (addr, prefix) = fields.next()?.split_once('/')?;
+    let addr = addr.parse::<IpAddr>().ok()?;
+    let prefix = prefix.parse::<u8>().ok()?;
+    let gateway = match fields.next() {
+        Some(gateway) => Some(gateway.parse::<IpAddr>().ok()?),
+        None => None,
+    };
+
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    if id == 0 || id > Vlan::MAX_ID || prefix > max_prefix || fields.next().is_some() {
+        return None;
+    }
+    if gateway.is_some_and(|gateway| gateway.is_ipv4() != addr.is_ipv4()) {
+        return None;
+    }
+    Some(VlanAddr {
+        id,
+        addr,
+        prefix,
+        gateway,
+    })
+}
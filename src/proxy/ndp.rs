@@ -0,0 +1,279 @@
+use socket2::{Domain, Protocol, SockRef, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    sync::Arc,
+};
+use tokio::time::{timeout, Duration, Instant};
+
+use ping_proxy::proto::{MacAddr, Reply, Request};
+
+use crate::ping::{create_socket, Ping};
+use crate::probe::{ProbeFuture, Prober};
+use crate::sys::{self, SendOptions};
+
+const ND_NEIGHBOR_SOLICIT: u8 = 135;
+const ND_NEIGHBOR_ADVERT: u8 = 136;
+const ND_OPT_SOURCE_LINKADDR: u8 = 1;
+const ND_OPT_TARGET_LINKADDR: u8 = 2;
+
+/// Neighbor discovery packets carry the largest hop limit, anything less
+/// has been routed and must be dropped.
+pub const ND_HOP_LIMIT: u8 = 255;
+
+///
+/// A neighbor solicitation goes to the target's solicited-node multicast
+/// group, or to the target itself when the request asks for unicast, on
+/// the interface the target is on link from. The advertisement answering
+/// it carries the link layer address and the router, solicited and
+/// override flags.
+///
+pub struct NdpProbe;
+
+impl Prober for NdpProbe {
+    fn run(&self, ping: Arc<Ping>, _source: SocketAddr, request: Request) -> ProbeFuture {
+        Box::pin(async move { ndp_probe(&ping, &request).await })
+    }
+}
+
+async fn ndp_probe(ping: &Ping, request: &Request) -> io::Result<Option<Reply>> {
+    let target = match request.host {
+        IpAddr::V6(target) => target,
+        IpAddr::V4(_) => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+    };
+    let (host, name) = (request.host, request.iface.clone());
+    let (iface, icmp) = ping
+        .in_netns(move || {
+            let iface = sys::interface_for(&host, name.as_deref())?;
+            let icmp = create_socket(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?;
+            Ok((iface, icmp))
+        })
+        .await?;
+    let mac = iface.mac.ok_or_else(|| {
+        let msg = format!("{} has no link layer address", iface.name);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+
+    {
+        let sock = SockRef::from(&icmp);
+        sock.bind_device(Some(iface.name.as_bytes()))?;
+        sock.set_unicast_hops_v6(ND_HOP_LIMIT as u32)?;
+        sock.set_multicast_hops_v6(ND_HOP_LIMIT as u32)?;
+    }
+    sys::set_recv_hoplimit(&icmp)?;
+    sys::pass_icmp6(&icmp, &[ND_NEIGHBOR_ADVERT])?;
+
+    let dest = if request.unicast {
+        target
+    } else {
+        solicited_node(&target)
+    };
+    let dest = SocketAddr::V6(SocketAddrV6::new(dest, 0, 0, iface.index));
+    let solicit = neighbor_solicit(&target, &mac);
+    let start = Instant::now();
+    sys::send_to(&icmp, &solicit, &dest, &SendOptions::default()).await?;
+
+    let wait = Duration::from_millis(request.timeout as u64);
+    let mut buf = [0u8; 1500];
+    let result = timeout(wait, async {
+        loop {
+            let recv = sys::recv_from(&icmp, &mut buf).await?;
+            if recv
+                .hop_limit
+                .is_some_and(|hop_limit| hop_limit != ND_HOP_LIMIT)
+            {
+                continue;
+            }
+            if let Some(advert) = neighbor_advert(&buf[..recv.len], &target) {
+                return Ok::<_, io::Error>((recv.from, advert));
+            }
+        }
+    })
+    .await;
+
+    if let Ok(Ok((from, (flags, mac)))) = result {
+        let elapse = start.elapsed().as_micros() as u32;
+        let mut reply = Reply::new(request.seq, elapse, ND_HOP_LIMIT);
+        reply.from = Some(from.ip());
+        reply.mac = mac.map(MacAddr);
+        reply.nd_flags = Some(flags);
+        return Ok(Some(reply));
+    }
+
+    Ok(None)
+}
+
+/// ff02::1:ff00:0/104 with the low 24 bits of the target.
+pub fn solicited_node(target: &Ipv6Addr) -> Ipv6Addr {
+    let octets = target.octets();
+    Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | octets[13] as u16,
+        u16::from_be_bytes([octets[14], octets[15]]),
+    )
+}
+
+//
+// | type(1B) | code(1B) | checksum(2B) | reserved(4B) | target(16B) | options |
+// the kernel fills in the checksum
+//
+pub fn neighbor_solicit(target: &Ipv6Addr, mac: &[u8; 6]) -> [u8; 32] {
+    let mut solicit = [0u8; 32];
+    solicit[0] = ND_NEIGHBOR_SOLICIT;
+    solicit[8..24].copy_from_slice(&target.octets());
+    solicit[24] = ND_OPT_SOURCE_LINKADDR;
+    solicit[25] = 1; // in units of 8 bytes
+    solicit[26..32].copy_from_slice(mac);
+    solicit
+}
+
+/// The solicited advertisement from `mac` answering a solicitation for
+/// `addr`, with the link layer address of the host that asked when it
+/// gave one. The checksum is left to the caller.
+pub fn neighbor_answer(
+    buf: &[u8],
+    addr: &Ipv6Addr,
+    mac: &[u8; 6],
+) -> Option<([u8; 32], Option<[u8; 6]>)> {
+    if buf.len() < 24 || buf[0] != ND_NEIGHBOR_SOLICIT || buf[1] != 0 {
+        return None;
+    }
+    if buf[8..24] != addr.octets() {
+        return None;
+    }
+
+    let mut asker = None;
+    let mut index = 24;
+    while index + 2 <= buf.len() {
+        let len = buf[index + 1] as usize * 8;
+        if len == 0 || index + len > buf.len() {
+            break;
+        }
+        if buf[index] == ND_OPT_SOURCE_LINKADDR && len >= 8 {
+            let mut addr = [0u8; 6];
+            addr.copy_from_slice(&buf[index + 2..index + 8]);
+            asker = Some(addr);
+        }
+        index += len;
+    }
+
+    let mut advert = [0u8; 32];
+    advert[0] = ND_NEIGHBOR_ADVERT;
+    advert[4] = 0x60; // solicited, override
+    advert[8..24].copy_from_slice(&addr.octets());
+    advert[24] = ND_OPT_TARGET_LINKADDR;
+    advert[25] = 1;
+    advert[26..32].copy_from_slice(mac);
+    Some((advert, asker))
+}
+
+/// The flags and target link layer address of an advertisement for `target`.
+pub fn neighbor_advert(buf: &[u8], target: &Ipv6Addr) -> Option<(u8, Option<[u8; 6]>)> {
+    if buf.len() < 24 || buf[0] != ND_NEIGHBOR_ADVERT || buf[1] != 0 {
+        return None;
+    }
+    if buf[8..24] != target.octets() {
+        return None;
+    }
+
+    let flags = buf[4] & 0xE0;
+    let mut mac = None;
+    let mut index = 24;
+    while index + 2 <= buf.len() {
+        let len = buf[index + 1] as usize * 8;
+        if len == 0 || index + len > buf.len() {
+            break;
+        }
+        if buf[index] == ND_OPT_TARGET_LINKADDR && len >= 8 {
+            let mut addr = [0u8; 6];
+            addr.copy_from_slice(&buf[index + 2..index + 8]);
+            mac = Some(addr);
+        }
+        index += len;
+    }
+
+    Some((flags, mac))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solicit_advert() {
+        let mac = [2, 0, 0, 0, 0, 1];
+        let addr: Ipv6Addr = "fd00::2".parse().unwrap();
+        let solicit = neighbor_solicit(&addr, &mac);
+        assert_eq!(solicit[0], ND_NEIGHBOR_SOLICIT);
+        assert_eq!(&solicit[8..24], &addr.octets());
+        assert_eq!(&solicit[26..32], &mac);
+        // a solicitation is no advertisement
+        assert_eq!(neighbor_advert(&solicit, &addr), None);
+    }
+
+    #[test]
+    fn advert() {
+        let ours = [2, 0, 0, 0, 0, 2];
+        let addr: Ipv6Addr = "fd00::2".parse().unwrap();
+        let mut advert = [0u8; 40];
+        advert[0] = ND_NEIGHBOR_ADVERT;
+        // router and solicited, the reserved bits are not flags
+        advert[4] = 0xC1;
+        advert[8..24].copy_from_slice(&addr.octets());
+        // an unknown option before the target link layer address
+        advert[24] = 14;
+        advert[25] = 1;
+        advert[32] = ND_OPT_TARGET_LINKADDR;
+        advert[33] = 1;
+        advert[34..40].copy_from_slice(&ours);
+        assert_eq!(neighbor_advert(&advert, &addr), Some((0xC0, Some(ours))));
+
+        // a zero option length ends the options
+        advert[25] = 0;
+        assert_eq!(neighbor_advert(&advert, &addr), Some((0xC0, None)));
+        assert_eq!(neighbor_advert(&advert[..24], &addr), Some((0xC0, None)));
+        // an option running past the end is left out
+        advert[25] = 1;
+        advert[33] = 2;
+        assert_eq!(neighbor_advert(&advert, &addr), Some((0xC0, None)));
+
+        assert!(neighbor_advert(&advert, &"fd00::3".parse().unwrap()).is_none());
+        assert!(neighbor_advert(&advert[..23], &addr).is_none());
+        advert[1] = 1;
+        assert!(neighbor_advert(&advert, &addr).is_none());
+    }
+
+    #[test]
+    fn solicited_node_group() {
+        let addr: Ipv6Addr = "fd00::1234:5678".parse().unwrap();
+        assert_eq!(
+            solicited_node(&addr),
+            "ff02::1:ff34:5678".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn answer() {
+        let asker = [2, 0, 0, 0, 0, 1];
+        let ours = [2, 0, 0, 0, 0, 2];
+        let addr: Ipv6Addr = "fd00::2".parse().unwrap();
+        let solicit = neighbor_solicit(&addr, &asker);
+
+        let (advert, from) = neighbor_answer(&solicit, &addr, &ours).unwrap();
+        assert_eq!(from, Some(asker));
+        assert_eq!(neighbor_advert(&advert, &addr), Some((0x60, Some(ours))));
+
+        // a solicitation without the source link layer option
+        let (_, from) = neighbor_answer(&solicit[..24], &addr, &ours).unwrap();
+        assert_eq!(from, None);
+
+        let other: Ipv6Addr = "fd00::3".parse().unwrap();
+        assert!(neighbor_answer(&solicit, &other, &ours).is_none());
+        assert!(neighbor_answer(&advert, &addr, &ours).is_none());
+    }
+}
@@ -1,18 +1,36 @@
-use socket2::{Domain, Protocol, Socket, Type};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::File,
     io,
     net::{IpAddr, SocketAddr},
     sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::net::UdpSocket;
+use tokio::{net::UdpSocket, sync::Notify};
 
 use buf_view::BufViewMut;
 
+use ping_proxy::proto::{Clock, Reply, Request, Status, Timestamps};
+
+use crate::ether::Ether;
 use crate::proxy::ProxyInfo;
+use crate::sys::{self, Netns, RecvInfo, SendOptions, Timestamp, TxStamps};
 
 pub const PING_MAGIC: u32 = 0x19170923;
 
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMPV6: u8 = 58;
+const ICMP_TIMESTAMP: u8 = 13;
+const ICMP_TIMESTAMP_REPLY: u8 = 14;
+/// Microseconds before its deadline a probe stops counting as in flight.
+const IN_FLIGHT_SLACK: u64 = 5000;
+/// Microseconds an answered or expired echo probe is remembered for the
+/// replies still coming to it.
+const RECENT_HOLD: u64 = 10_000_000;
+
 #[derive(Debug)]
 enum IcmpError {
     Magic,
@@ -20,6 +38,8 @@ enum IcmpError {
     Type,
     Checksum,
     ID,
+    Length,
+    Pending,
 }
 
 impl std::fmt::Display for IcmpError {
@@ -30,62 +50,263 @@ impl std::fmt::Display for IcmpError {
             IcmpError::Type => write!(f, "Invalid ICMP type"),
             IcmpError::Checksum => write!(f, "Invalid checksum"),
             IcmpError::ID => write!(f, "Invalid ID"),
+            IcmpError::Length => write!(f, "Invalid length"),
+            IcmpError::Pending => write!(f, "No pending probe"),
         }
     }
 }
 
 impl std::error::Error for IcmpError {}
 
+/// Identifies an outstanding probe in the headers an ICMP error quotes.
+/// ICMP probes are keyed by echo identifier and sequence, UDP and TCP ones
+/// by their local port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProbeKey {
+    Icmp(u16, u16),
+    Udp(u16),
+    Tcp(u16),
+}
+
+#[derive(Debug, Clone)]
+pub struct Pending {
+    pub source: SocketAddr,
+    pub seq: u32,
+    pub tx_time: u64,
+    // kernel transmit timestamp, only taken for ICMP probes
+    tx_stamp: Option<Timestamp>,
+    // the echo carries our private data, too small ones are matched by
+    // identifier and sequence alone
+    private: bool,
+    // a broadcast or multicast probe, every responder is answered until
+    // the deadline, each one once
+    multi: bool,
+    responders: Vec<IpAddr>,
+    deadline: u64,
+    // wakes the task of a UDP or TCP probe once an ICMP error answered it
+    claimed: Option<Arc<Notify>>,
+}
+
+/// An echo probe answered or expired a moment ago, another reply to it is
+/// passed on as a duplicate or a late one, never taken for a new probe.
+#[derive(Debug)]
+struct Recent {
+    pending: Pending,
+    answered: bool,
+    until: u64,
+}
+
 #[derive(Debug)]
 pub struct Ping {
+    // random per instance, so proxies on one host do not take each
+    // other's replies
     identifier: u16,
+    // next echo sequence to try, sequences still in flight are skipped
     seq: Arc<Mutex<u16>>,
     pid: u32,
     socket4: UdpSocket,
     socket6: UdpSocket,
+    // send only sockets for don't fragment probes, replies come in on the
+    // sockets above
+    socket4_df: UdpSocket,
+    socket6_df: UdpSocket,
+    // the probes sent on each of the sockets above that wait for their
+    // transmit timestamp
+    stamps4: TxStamps<ProbeKey>,
+    stamps6: TxStamps<ProbeKey>,
+    stamps4_df: TxStamps<ProbeKey>,
+    stamps6_df: TxStamps<ProbeKey>,
+    // echo requests go out as frames of our own when set
+    ether: Option<Ether>,
+    // the thread in the network namespace the sockets live in, the proxy's
+    // own namespace when not set
+    netns: Option<Netns>,
     uptime: Instant,
+    pending: Mutex<HashMap<ProbeKey, Pending>>,
+    recent: Mutex<HashMap<ProbeKey, Recent>>,
 }
 
 impl Ping {
-    pub async fn new() -> io::Result<Ping> {
+    pub fn new(ether: Option<Ether>) -> io::Result<Ping> {
         let sock4 = create_socket(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
         let sock6 = create_socket(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?;
+        sys::set_recv_hoplimit(&sock6)?;
+        sys::set_recv_tos(&sock6, false)?;
+
+        let sock4_df = create_socket(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
+        sys::set_dont_fragment(&sock4_df, true)?;
+        sys::block_icmp(&sock4_df, true)?;
+        for socket in [&sock4, &sock4_df] {
+            SockRef::from(socket).set_broadcast(true)?;
+        }
+        let sock6_df = create_socket(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?;
+        sys::set_dont_fragment(&sock6_df, false)?;
+        sys::block_icmp(&sock6_df, false)?;
+
+        // without kernel timestamps times are taken in userspace
+        for socket in [&sock4, &sock6, &sock4_df, &sock6_df] {
+            let _ = sys::set_timestamping(socket);
+        }
 
         Ok(Ping {
-            identifier: 0x1917,
-            seq: Arc::new(Mutex::new(0x0923)),
+            identifier: sys::random_u16(),
+            seq: Arc::new(Mutex::new(sys::random_u16())),
             pid: std::process::id(),
             socket4: sock4,
             socket6: sock6,
+            socket4_df: sock4_df,
+            socket6_df: sock6_df,
+            stamps4: TxStamps::new(),
+            stamps6: TxStamps::new(),
+            stamps4_df: TxStamps::new(),
+            stamps6_df: TxStamps::new(),
+            ether,
+            netns: None,
             uptime: Instant::now(),
+            pending: Mutex::new(HashMap::new()),
+            recent: Mutex::new(HashMap::new()),
         })
     }
 
-    pub async fn send_to(
-        &self,
-        source: &SocketAddr,
-        target: &SocketAddr,
-        seq: u32,
-        len: usize,
-    ) -> io::Result<usize> {
-        let mut buf = [0u8; 1024 * 64];
-        assert!(len < buf.len());
+    /// An engine with its sockets in the network namespace `netns`.
+    pub async fn new_in(netns: File) -> io::Result<Ping> {
+        let netns = Netns::new(netns).await?;
+        let mut ping = netns.run(|| Ping::new(None)).await?;
+        ping.netns = Some(netns);
+        Ok(ping)
+    }
+
+    /// Runs `f` in the engine's network namespace, probes open their
+    /// sockets through it so they leave from the same namespace.
+    pub async fn in_netns<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        match &self.netns {
+            Some(netns) => netns.run(f).await,
+            None => f(),
+        }
+    }
+
+    pub async fn send_to(&self, source: &SocketAddr, request: &Request) -> io::Result<usize> {
+        let len = request.length as usize;
+        let mut buf = vec![0u8; 8 + len];
         let mut buf = BufViewMut::wrap(&mut buf);
-        self.icmp_request_build(seq, source, len, &mut buf);
-        let socket = if target.is_ipv4() {
-            &self.socket4
-        } else {
-            &self.socket6
+        let target = self.target(request).await?;
+        let private = len >= private_len(source);
+        let seq = self.allocate(source, request, private)?;
+        self.icmp_request_build(seq, request, source, private, &mut buf);
+
+        if let Some(ether) = &self.ether {
+            if let Err(err) = ether.send(buf.as_slice(), request).await {
+                self.claim(&ProbeKey::Icmp(self.identifier, seq));
+                return Err(err);
+            }
+            return Ok(len);
+        }
+
+        let (socket, stamps) = self.socket(target.is_ipv4(), request.df);
+        let opts = SendOptions {
+            ttl: request.ttl,
+            tos: request.tos,
         };
-        socket.send_to(buf.as_slice(), target).await?;
+        let key = ProbeKey::Icmp(self.identifier, seq);
+        if let Err(err) = stamps
+            .send_to(socket, buf.as_slice(), &target, &opts, key)
+            .await
+        {
+            self.claim(&key);
+            return Err(err);
+        }
+        self.tx_stamps(socket, stamps);
 
         Ok(len)
     }
 
+    /// Sends an ICMP timestamp request, IPv4 only, in a frame of our own in
+    /// raw Ethernet mode. The reply is matched by identifier and sequence
+    /// like an echo reply.
+    pub async fn send_timestamp(&self, source: &SocketAddr, request: &Request) -> io::Result<()> {
+        let seq = self.allocate(source, request, false)?;
+
+        //
+        // | type(1B) | code(1B) | checksum(2B) | identifier(2B) | seq(2B) |
+        // | originate(4B) | receive(4B) | transmit(4B) |
+        //
+        let mut buf = [0u8; 20];
+        buf[0] = ICMP_TIMESTAMP;
+        buf[4..6].copy_from_slice(&self.identifier.to_be_bytes());
+        buf[6..8].copy_from_slice(&seq.to_be_bytes());
+        buf[8..12].copy_from_slice(&utc_millis().to_be_bytes());
+        let checksum = ip_checksum(&mut buf);
+        buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+        if let Some(ether) = &self.ether {
+            if let Err(err) = ether.send(&buf, request).await {
+                self.claim(&ProbeKey::Icmp(self.identifier, seq));
+                return Err(err);
+            }
+            return Ok(());
+        }
+
+        let (socket, stamps) = self.socket(true, request.df);
+        let target = SocketAddr::new(request.host, 0);
+        let opts = SendOptions {
+            ttl: request.ttl,
+            tos: request.tos,
+        };
+        let key = ProbeKey::Icmp(self.identifier, seq);
+        if let Err(err) = stamps.send_to(socket, &buf, &target, &opts, key).await {
+            self.claim(&key);
+            return Err(err);
+        }
+        self.tx_stamps(socket, stamps);
+        Ok(())
+    }
+
+    /// The socket ICMP probes go out on, by family and don't fragment.
+    fn socket(&self, ipv4: bool, df: bool) -> (&UdpSocket, &TxStamps<ProbeKey>) {
+        match (ipv4, df) {
+            (true, false) => (&self.socket4, &self.stamps4),
+            (false, false) => (&self.socket6, &self.stamps6),
+            (true, true) => (&self.socket4_df, &self.stamps4_df),
+            (false, true) => (&self.socket6_df, &self.stamps6_df),
+        }
+    }
+
+    /// Where a probe goes, a multicast group with the scope of the
+    /// requested interface.
+    async fn target(&self, request: &Request) -> io::Result<SocketAddr> {
+        let mut target = SocketAddr::new(request.host, 0);
+        if let (SocketAddr::V6(addr), Some(name)) = (&mut target, &request.iface) {
+            if addr.ip().is_multicast() {
+                let (host, name) = (request.host, name.clone());
+                let iface = self
+                    .in_netns(move || sys::interface_for(&host, Some(&name)))
+                    .await?;
+                addr.set_scope_id(iface.index);
+            }
+        }
+        Ok(target)
+    }
+
     pub async fn recv_from_v4(&self) -> Option<ProxyInfo> {
         let mut buf = [0u8; 1024 * 64];
-        if let Ok((len, _)) = self.socket4.recv_from(&mut buf).await {
-            if let Ok(info) = self.parse(&mut buf[..len]) {
+        if let Ok(mut recv) = sys::recv_from(&self.socket4, &mut buf).await {
+            let buf = &mut buf[..recv.len];
+            if buf.len() < 20 || buf[0] >> 4 != 4 {
+                return None;
+            }
+            recv.tos = Some(buf[1]);
+            recv.hop_limit = Some(buf[8]);
+            self.tx_stamps(&self.socket4, &self.stamps4);
+            self.tx_stamps(&self.socket4_df, &self.stamps4_df);
+            let icmp_offset = ((buf[0] & 0xF) * 4) as usize;
+            if buf.len() > icmp_offset && self.ether_reply(buf[icmp_offset], false) {
+                return None;
+            }
+            if let Ok(info) = self.parse(buf, icmp_offset, false, &recv) {
                 return Some(info);
             }
         }
@@ -95,8 +316,47 @@ impl Ping {
 
     pub async fn recv_from_v6(&self) -> Option<ProxyInfo> {
         let mut buf = [0u8; 1024 * 64];
-        if let Ok((len, _)) = self.socket6.recv_from(&mut buf).await {
-            if let Ok(info) = self.parse(&mut buf[..len]) {
+        if let Ok(recv) = sys::recv_from(&self.socket6, &mut buf).await {
+            // raw ICMPv6 sockets deliver no IP header, the hop limit and
+            // traffic class come as ancillary data
+            let buf = &mut buf[..recv.len];
+            self.tx_stamps(&self.socket6, &self.stamps6);
+            self.tx_stamps(&self.socket6_df, &self.stamps6_df);
+            if !buf.is_empty() && self.ether_reply(buf[0], true) {
+                return None;
+            }
+            if let Ok(info) = self.parse(buf, 0, true, &recv) {
+                return Some(info);
+            }
+        }
+
+        None
+    }
+
+    pub fn ether_enabled(&self) -> bool {
+        self.ether.is_some()
+    }
+
+    /// Whether a reply the kernel sockets got is left to the raw Ethernet
+    /// socket. Echo and timestamp requests all go out as frames there and
+    /// their replies come back on it, the kernel sockets see them too when
+    /// the configured MAC is the interface's own and taking both would make
+    /// every reply a duplicate.
+    fn ether_reply(&self, icmp_type: u8, v6: bool) -> bool {
+        let echo_reply = if v6 { 129 } else { 0 };
+        self.ether.is_some()
+            && (icmp_type == echo_reply || (!v6 && icmp_type == ICMP_TIMESTAMP_REPLY))
+    }
+
+    /// Replies taken off the raw Ethernet socket. ICMP errors reach the
+    /// kernel sockets as well when the configured MAC is the interface's
+    /// own, whichever path claims the probe first answers it.
+    pub async fn recv_from_ether(&self) -> Option<ProxyInfo> {
+        let ether = self.ether.as_ref()?;
+        let mut buf = [0u8; 1024 * 64];
+        if let Ok(packet) = ether.recv(&mut buf).await {
+            let buf = &mut buf[packet.range.clone()];
+            if let Ok(info) = self.parse(buf, packet.icmp_offset, packet.v6, &packet.recv) {
                 return Some(info);
             }
         }
@@ -104,35 +364,254 @@ impl Ping {
         None
     }
 
-    fn parse(&self, buf: &mut [u8]) -> Result<ProxyInfo, IcmpError> {
+    /// Records a probe that was just sent so replies and ICMP errors can be
+    /// matched to the client that asked for it. The probe's task is notified
+    /// once an ICMP error answered it, there is nothing left to wait for.
+    pub fn register(
+        &self,
+        key: ProbeKey,
+        source: &SocketAddr,
+        seq: u32,
+        timeout: u16,
+    ) -> Arc<Notify> {
+        let claimed = Arc::new(Notify::new());
+        let mut pending = self.pending_new(source, seq, timeout, false);
+        pending.claimed = Some(claimed.clone());
+        self.pending.lock().unwrap().insert(key, pending);
+        claimed
+    }
+
+    /// Picks the next echo sequence that is not in flight nor recent and
+    /// registers the probe under it, both under the pending lock so no two
+    /// probes share a sequence.
+    fn allocate(&self, source: &SocketAddr, request: &Request, private: bool) -> io::Result<u16> {
+        let mut pending = self.pending.lock().unwrap();
+        let recent = self.recent.lock().unwrap();
+        let mut next = self.seq.lock().unwrap();
+        for _ in 0..=u16::MAX {
+            let icmp_seq = *next;
+            *next = icmp_seq.wrapping_add(1);
+            let key = ProbeKey::Icmp(self.identifier, icmp_seq);
+            if recent.contains_key(&key) {
+                continue;
+            }
+            if let Entry::Vacant(entry) = pending.entry(key) {
+                let mut probe = self.pending_new(source, request.seq, request.timeout, private);
+                probe.multi = request.multi;
+                entry.insert(probe);
+                return Ok(icmp_seq);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "every echo sequence is in flight",
+        ))
+    }
+
+    fn pending_new(&self, source: &SocketAddr, seq: u32, timeout: u16, private: bool) -> Pending {
+        let tx_time = self.elapsed().as_micros() as u64;
+        Pending {
+            source: *source,
+            seq,
+            tx_time,
+            tx_stamp: None,
+            private,
+            multi: false,
+            responders: Vec::new(),
+            deadline: tx_time + timeout as u64 * 1000,
+            claimed: None,
+        }
+    }
+
+    /// How many probes of `source` wait for an answer, the expired ones
+    /// the next sweep drops do not. The client's timer starts before ours,
+    /// probes about to expire already count as gone.
+    pub fn in_flight(&self, source: &SocketAddr) -> usize {
+        let now = self.elapsed().as_micros() as u64 + IN_FLIGHT_SLACK;
+        let pending = self.pending.lock().unwrap();
+        pending
+            .values()
+            .filter(|probe| probe.source == *source && probe.deadline > now)
+            .count()
+    }
+
+    /// Takes the probe out of the pending table, only the first caller gets it,
+    /// so a probe is answered once.
+    pub fn claim(&self, key: &ProbeKey) -> Option<Pending> {
+        self.pending.lock().unwrap().remove(key)
+    }
+
+    /// The probe a reply or ICMP error is for. A broadcast or multicast
+    /// probe stays pending for the other responders until its deadline,
+    /// any other is claimed. A responder seen on both the raw link and the
+    /// ICMP socket is answered once.
+    fn answer(&self, key: &ProbeKey, from: IpAddr) -> Option<Pending> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get_mut(key) {
+            Some(probe) if probe.multi => {
+                let now = self.elapsed().as_micros() as u64;
+                if probe.deadline <= now || probe.responders.contains(&from) {
+                    return None;
+                }
+                probe.responders.push(from);
+                Some(probe.clone())
+            }
+            Some(_) => {
+                let probe = pending.remove(key)?;
+                if let Some(claimed) = &probe.claimed {
+                    claimed.notify_one();
+                }
+                self.remember(key, &probe, true);
+                Some(probe)
+            }
+            None => None,
+        }
+    }
+
+    /// Keeps an echo probe that was answered or expired for the replies
+    /// still to come.
+    fn remember(&self, key: &ProbeKey, probe: &Pending, answered: bool) {
+        if !matches!(key, ProbeKey::Icmp(..)) || probe.multi {
+            return;
+        }
+        let until = self.elapsed().as_micros() as u64 + RECENT_HOLD;
+        let recent = Recent {
+            pending: probe.clone(),
+            answered,
+            until,
+        };
+        self.recent.lock().unwrap().insert(*key, recent);
+    }
+
+    /// The probe a reply to an answered or expired probe is for, and
+    /// whether it is a duplicate, the first reply after the timeout is late.
+    fn answer_again(&self, key: &ProbeKey) -> Option<(Pending, bool)> {
+        let mut recent = self.recent.lock().unwrap();
+        let probe = recent.get_mut(key)?;
+        let duplicate = probe.answered;
+        probe.answered = true;
+        Some((probe.pending.clone(), duplicate))
+    }
+
+    /// Drops the probes nobody answered in time. The transmit timestamps
+    /// are drained too, a socket no reply comes in on would hold them.
+    pub fn expire(&self) {
+        let sockets = [
+            (&self.socket4, &self.stamps4),
+            (&self.socket6, &self.stamps6),
+            (&self.socket4_df, &self.stamps4_df),
+            (&self.socket6_df, &self.stamps6_df),
+        ];
+        for (socket, stamps) in sockets {
+            self.tx_stamps(socket, stamps);
+        }
+
+        let now = self.elapsed().as_micros() as u64;
+        let mut pending = self.pending.lock().unwrap();
+        let expired: Vec<ProbeKey> = pending
+            .iter()
+            .filter(|(_, probe)| probe.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            if let Some(probe) = pending.remove(&key) {
+                self.remember(&key, &probe, false);
+            }
+        }
+        self.recent
+            .lock()
+            .unwrap()
+            .retain(|_, recent| recent.until > now);
+        for (_, stamps) in sockets {
+            stamps.retain(|key| pending.contains_key(key));
+        }
+    }
+
+    /// Transmit timestamps come back with the number of the packet they
+    /// were taken for, `TxStamps` knows the probe.
+    fn tx_stamps(&self, socket: &UdpSocket, stamps: &TxStamps<ProbeKey>) {
+        for (key, stamp) in stamps.recv(socket) {
+            if let Some(pending) = self.pending.lock().unwrap().get_mut(&key) {
+                pending.tx_stamp = Some(stamp);
+            }
+        }
+    }
+
+    fn parse(
+        &self,
+        buf: &mut [u8],
+        icmp_offset: usize,
+        v6: bool,
+        recv: &RecvInfo,
+    ) -> Result<ProxyInfo, IcmpError> {
         let now = self.elapsed().as_micros() as u64;
         let len = buf.len();
+        if len < icmp_offset + 8 {
+            return Err(IcmpError::Length);
+        }
         let mut buf = BufViewMut::wrap_with(buf, 0, len);
 
-        let ihl = buf.get_u8(0);
-        let ver = ihl >> 4;
-        let ttl;
-        let icmp_offset;
-
-        if ver == 4 {
-            ttl = buf.get_u8(8);
-            icmp_offset = ((ihl & 0xF) * 4) as usize;
-            let icmp_type = buf.get_u8(icmp_offset);
-            if icmp_type != 0 {
-                return Err(IcmpError::Type);
-            }
-        } else if ver == 6 {
-            ttl = buf.get_u8(7);
-            icmp_offset = 40usize;
-            let icmp_type = buf.get_u8(icmp_offset);
-            if icmp_type != 129 {
-                return Err(IcmpError::Type);
-            }
-        } else {
-            return Err(IcmpError::IpHeader);
+        let icmp_type = buf.get_u8(icmp_offset);
+        let icmp_code = buf.get_u8(icmp_offset + 1);
+        let (echo_reply, time_exceeded, unreachable) = if v6 { (129, 3, 1) } else { (0, 11, 3) };
+
+        // ICMPv6 packet too big, ICMP fragmentation needed
+        if (v6 && icmp_type == 2) || (!v6 && icmp_type == 3 && icmp_code == 4) {
+            let mtu = if v6 {
+                buf.get_u32(icmp_offset + 4).min(u16::MAX as u32) as u16
+            } else {
+                buf.get_u16(icmp_offset + 6)
+            };
+            let mut info =
+                self.parse_error(&mut buf, icmp_offset + 8, v6, now, recv, Status::TooBig, 0)?;
+            // a zero MTU comes from a pre RFC 1191 router
+            info.reply.mtu = Some(mtu).filter(|mtu| *mtu != 0);
+            return Ok(info);
+        }
+
+        if icmp_type == time_exceeded || icmp_type == unreachable {
+            let status = if icmp_type == time_exceeded {
+                Status::TimeExceeded
+            } else {
+                Status::Unreachable
+            };
+            return self.parse_error(&mut buf, icmp_offset + 8, v6, now, recv, status, icmp_code);
+        }
+
+        if !v6 && icmp_type == ICMP_TIMESTAMP_REPLY {
+            return self.timestamp_reply(&mut buf, icmp_offset, now, recv);
+        }
+
+        if icmp_type != echo_reply {
+            return Err(IcmpError::Type);
+        }
+
+        if buf.get_u16(icmp_offset + 4) != self.identifier {
+            return Err(IcmpError::ID);
+        }
+        let icmp_seq = buf.get_u16(icmp_offset + 6);
+        let key = ProbeKey::Icmp(self.identifier, icmp_seq);
+        let private = self
+            .pending
+            .lock()
+            .unwrap()
+            .get(&key)
+            .map(|pending| pending.private)
+            .or_else(|| {
+                let recent = self.recent.lock().unwrap();
+                recent.get(&key).map(|recent| recent.pending.private)
+            });
+        match private {
+            Some(true) => {}
+            Some(false) => return self.echo_reply(&key, now, recv),
+            None => return Err(IcmpError::Pending),
         }
 
         let magic_index = icmp_offset + 8;
+        if len < magic_index + 27 {
+            return Err(IcmpError::Length);
+        }
         buf.set_reader_index(magic_index);
         let magic = buf.read_u32();
         if magic != PING_MAGIC {
@@ -147,97 +626,209 @@ impl Ping {
             return Err(IcmpError::ID);
         }
 
-        let seq = buf.read_u32();
-        let tx_time = buf.read_u64();
-        let port = buf.read_u16();
-        let len = buf.read_u8();
+        // client seq, tx time and port, the probe itself is found by
+        // (identifier, seq) in the pending table
+        buf.set_reader_index(buf.reader_index() + 14);
+        let len = buf.read_u8() as usize;
+        if buf.remaining() < len {
+            return Err(IcmpError::Length);
+        }
+        let index = buf.reader_index() + len;
 
-        let host = if len == 4 {
-            let mut v4 = [0u8; 4];
-            buf.read_bytes(&mut v4);
-            IpAddr::from(v4)
-        } else {
-            let mut v6 = [0u8; 16];
-            buf.read_bytes(&mut v6);
-            IpAddr::from(v6)
+        if checksum != ip_checksum(&mut buf.as_raw_slice()[magic_index..index]) {
+            return Err(IcmpError::Checksum);
+        }
+
+        self.echo_reply(&key, now, recv)
+    }
+
+    fn echo_reply(
+        &self,
+        key: &ProbeKey,
+        now: u64,
+        recv: &RecvInfo,
+    ) -> Result<ProxyInfo, IcmpError> {
+        let (pending, duplicate) = match self.answer(key, recv.from.ip()) {
+            Some(pending) => (pending, false),
+            None => self.answer_again(key).ok_or(IcmpError::Pending)?,
         };
+        let elapse = (now - pending.tx_time) as u32;
+        let mut reply = self.reply(pending.seq, elapse, Some(&pending), recv);
+        reply.duplicate = duplicate;
 
-        let index = buf.reader_index();
+        Ok(ProxyInfo {
+            target: pending.source,
+            reply,
+        })
+    }
 
-        if checksum != ip_checksum(&mut buf.as_raw_slice()[magic_index..index]) {
-            return Err(IcmpError::Checksum);
+    fn timestamp_reply(
+        &self,
+        buf: &mut BufViewMut,
+        icmp_offset: usize,
+        now: u64,
+        recv: &RecvInfo,
+    ) -> Result<ProxyInfo, IcmpError> {
+        let arrival = utc_millis();
+        if buf.writer_index() < icmp_offset + 20 {
+            return Err(IcmpError::Length);
+        }
+        if buf.get_u16(icmp_offset + 4) != self.identifier {
+            return Err(IcmpError::ID);
+        }
+        let key = ProbeKey::Icmp(self.identifier, buf.get_u16(icmp_offset + 6));
+        let mut info = self.echo_reply(&key, now, recv)?;
+        info.reply.timestamps = Some(Timestamps {
+            originate: buf.get_u32(icmp_offset + 8),
+            receive: buf.get_u32(icmp_offset + 12),
+            transmit: buf.get_u32(icmp_offset + 16),
+            arrival,
+        });
+        Ok(info)
+    }
+
+    //
+    // ICMP errors quote the offending packet from its IP header on, at least
+    // the first 8 bytes of the transport header which is all we need.
+    //
+    #[allow(clippy::too_many_arguments)]
+    fn parse_error(
+        &self,
+        buf: &mut BufViewMut,
+        quote: usize,
+        v6: bool,
+        now: u64,
+        recv: &RecvInfo,
+        status: Status,
+        code: u8,
+    ) -> Result<ProxyInfo, IcmpError> {
+        let len = buf.writer_index();
+        let (protocol, transport) = if v6 {
+            if len < quote + 40 {
+                return Err(IcmpError::Length);
+            }
+            (buf.get_u8(quote + 6), quote + 40)
+        } else {
+            if len < quote + 20 || buf.get_u8(quote) >> 4 != 4 {
+                return Err(IcmpError::IpHeader);
+            }
+            let ihl = ((buf.get_u8(quote) & 0xF) * 4) as usize;
+            (buf.get_u8(quote + 9), quote + ihl)
+        };
+
+        if len < transport + 8 {
+            return Err(IcmpError::Length);
         }
 
-        let target = SocketAddr::new(host, port);
-        let elapse = (now - tx_time) as u32;
+        let key = match protocol {
+            IPPROTO_ICMP | IPPROTO_ICMPV6 => {
+                ProbeKey::Icmp(buf.get_u16(transport + 4), buf.get_u16(transport + 6))
+            }
+            IPPROTO_UDP => ProbeKey::Udp(buf.get_u16(transport)),
+            IPPROTO_TCP => ProbeKey::Tcp(buf.get_u16(transport)),
+            _ => return Err(IcmpError::Type),
+        };
+
+        let pending = self
+            .answer(&key, recv.from.ip())
+            .ok_or(IcmpError::Pending)?;
+        let elapse = (now - pending.tx_time) as u32;
+        let mut reply = self.reply(pending.seq, elapse, Some(&pending), recv);
+        reply.status = status;
+        reply.code = code;
 
         Ok(ProxyInfo {
-            target,
-            seq,
-            elapse,
-            ttl,
+            target: pending.source,
+            reply,
         })
     }
 
+    /// Builds the reply, the userspace `elapse` is replaced by the kernel
+    /// one when the probe has both timestamps from the same clock.
+    fn reply(&self, seq: u32, elapse: u32, pending: Option<&Pending>, recv: &RecvInfo) -> Reply {
+        let mut reply = Reply::new(seq, elapse, recv.hop_limit.unwrap_or(0));
+        reply.from = Some(recv.from.ip());
+        reply.tos = recv.tos;
+
+        let tx = pending.and_then(|pending| pending.tx_stamp);
+        if let (Some(tx), Some(rx)) = (tx, recv.stamp) {
+            let stamps = [
+                (Clock::Hardware, tx.hardware, rx.hardware),
+                (Clock::Software, tx.software, rx.software),
+            ];
+            for (clock, tx, rx) in stamps {
+                if let (Some(tx), Some(rx)) = (tx, rx) {
+                    if rx >= tx {
+                        reply.elapse = ((rx - tx) / 1000) as u32;
+                        reply.clock = clock;
+                        break;
+                    }
+                }
+            }
+        }
+
+        reply
+    }
+
     //
     // see https://en.wikipedia.org/wiki/Internet_Control_Message_Protocol
     //
     fn icmp_request_build(
         &self,
-        client_seq: u32,
+        seq: u16,
+        request: &Request,
         addr: &SocketAddr,
-        len: usize,
+        private: bool,
         buf: &mut BufViewMut,
     ) {
-        let icmp_type = if addr.ip().is_ipv4() { 8 } else { 128 };
+        let icmp_type = if request.host.is_ipv4() { 8 } else { 128 };
         buf.write_u8(icmp_type); //type
         buf.write_u8(0); //code
         buf.write_u16(0); //checksum
         buf.write_u16(self.identifier);
-
-        let seq;
-        {
-            let mut mseq = self.seq.lock().unwrap();
-            seq = *mseq;
-            *mseq = seq.overflowing_add(1).0;
-        }
         buf.write_u16(seq);
 
-        //
-        // private data
-        // checksum from magic to host
-        // | magic(4B) | checksum(2B) | pid(4B) | client seq(4B) | micro_sec(8B) | port(2B) | host length(1B) | host |
-        //
-        let magic_index = buf.writer_index();
-        buf.write_u32(PING_MAGIC);
-        buf.write_u16(0); //clear checksum
-        buf.write_u32(self.pid);
-        let now = self.uptime.elapsed();
-        buf.write_u32(client_seq);
-        buf.write_u64(now.as_micros() as u64);
-        buf.write_u16(addr.port());
-
-        match addr.ip() {
-            IpAddr::V4(ip) => {
-                buf.write_u8(4);
-                buf.write_bytes(&ip.octets());
-            }
-            IpAddr::V6(ip) => {
-                buf.write_u8(16);
-                buf.write_bytes(&ip.octets());
+        if private {
+            //
+            // private data
+            // checksum from magic to host
+            // | magic(4B) | checksum(2B) | pid(4B) | client seq(4B) | micro_sec(8B) | port(2B) | host length(1B) | host |
+            //
+            let magic_index = buf.writer_index();
+            buf.write_u32(PING_MAGIC);
+            buf.write_u16(0); //clear checksum
+            buf.write_u32(self.pid);
+            let now = self.uptime.elapsed();
+            buf.write_u32(request.seq);
+            buf.write_u64(now.as_micros() as u64);
+            buf.write_u16(addr.port());
+
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    buf.write_u8(4);
+                    buf.write_bytes(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    buf.write_u8(16);
+                    buf.write_bytes(&ip.octets());
+                }
             }
-        }
 
-        let checksum = ip_checksum(&mut buf.as_slice()[magic_index..]);
-        buf.set_u16(magic_index + 4, checksum);
+            let checksum = ip_checksum(&mut buf.as_slice()[magic_index..]);
+            buf.set_u16(magic_index + 4, checksum);
+        }
 
         let index = buf.writer_index();
-        for i in 0..(len - index) {
-            buf.write_u8((i & 0xFF) as u8);
+        let mut padding = vec![0u8; 8 + request.length as usize - index];
+        request.payload.fill(&mut padding);
+        buf.write_bytes(&padding);
+
+        // the kernel fills in the ICMPv6 checksum, it covers a pseudo header,
+        // raw frames get theirs when the IPv6 header is built
+        if request.host.is_ipv4() {
+            let checksum = ip_checksum(buf.as_slice());
+            buf.set_u16(2, checksum);
         }
-
-        let checksum = ip_checksum(buf.as_slice());
-        buf.set_u16(2, checksum);
     }
 
     pub fn elapsed(&self) -> Duration {
@@ -245,7 +836,25 @@ impl Ping {
     }
 }
 
-fn create_socket(domain: Domain, typ: Type, protocol: Option<Protocol>) -> io::Result<UdpSocket> {
+/// Milliseconds since midnight UTC, the ICMP timestamp clock.
+fn utc_millis() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_millis() % 86_400_000) as u32
+}
+
+/// Length of the private data an echo request to `addr`'s client carries.
+fn private_len(addr: &SocketAddr) -> usize {
+    let host = if addr.is_ipv4() { 4 } else { 16 };
+    4 + 2 + 4 + 4 + 8 + 2 + 1 + host
+}
+
+pub fn create_socket(
+    domain: Domain,
+    typ: Type,
+    protocol: Option<Protocol>,
+) -> io::Result<UdpSocket> {
     let socket = Socket::new(domain, typ, protocol)?;
     socket.set_nonblocking(true)?;
     let _ = socket.set_recv_buffer_size(1 << 20);
@@ -264,7 +873,7 @@ fn create_socket(domain: Domain, typ: Type, protocol: Option<Protocol>) -> io::R
     UdpSocket::from_std(socket)
 }
 
-fn ip_checksum(buf: &mut [u8]) -> u16 {
+pub fn ip_checksum(buf: &mut [u8]) -> u16 {
     let odd = (buf.len() & 1) == 1;
     let len = if odd { buf.len() - 1 } else { buf.len() };
 
@@ -277,7 +886,7 @@ fn ip_checksum(buf: &mut [u8]) -> u16 {
     }
 
     if odd {
-        sum += buf[index] as u32;
+        sum += (buf[index] as u32) << 8;
     }
 
     sum = (sum >> 16) + (sum & 0xFFFF);
@@ -285,3 +894,31 @@ fn ip_checksum(buf: &mut [u8]) -> u16 {
 
     !sum as u16
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        // RFC 1071 example words
+        let mut buf = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(ip_checksum(&mut buf), !0xddf2);
+
+        // a checksummed message sums to zero
+        let mut buf = [8, 0, 0, 0, 0x12, 0x34, 0, 1, 0xab, 0xcd];
+        let checksum = ip_checksum(&mut buf);
+        buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(ip_checksum(&mut buf), 0);
+    }
+
+    #[test]
+    fn checksum_odd_length() {
+        // the last byte is padded with a zero, not dropped
+        let mut odd = [8, 0, 0, 0, 0xab];
+        let mut even = [8, 0, 0, 0, 0xab, 0];
+        assert_eq!(ip_checksum(&mut odd), ip_checksum(&mut even));
+        assert_ne!(ip_checksum(&mut odd), ip_checksum(&mut [8, 0, 0, 0]));
+        assert_eq!(ip_checksum(&mut [0xff]), 0x00ff);
+    }
+}
@@ -0,0 +1,76 @@
+use std::{collections::HashMap, future::Future, io, net::SocketAddr, pin::Pin, sync::Arc};
+
+use ping_proxy::proto::{Probe, Reply, Request};
+
+use crate::arp::ArpProbe;
+use crate::coap::CoapProbe;
+use crate::echo::EchoProbe;
+use crate::ndp::NdpProbe;
+use crate::ping::Ping;
+use crate::tcp::TcpProbe;
+use crate::timestamp::TimestampProbe;
+use crate::udp::UdpProbe;
+
+/// The outcome of a probe, `None` when there is nothing to answer yet, the
+/// ICMP engine answers probes whose reply comes in as ICMP itself.
+pub type ProbeFuture = Pin<Box<dyn Future<Output = io::Result<Option<Reply>>> + Send>>;
+
+///
+/// A probe kind of the proxy. The server looks the request's probe type up
+/// in `Probers`, runs it on a task of its own and sends the client the
+/// reply it comes back with, or the error as an invalid reply.
+///
+pub trait Prober: Send + Sync {
+    /// Refuses a request the probe cannot carry out on `ping`, the reason
+    /// goes back to the client.
+    fn check(&self, ping: &Ping, request: &Request) -> Result<(), String> {
+        let _ = ping;
+        if request.vlan.is_some() {
+            return Err("VLAN tags only go on echo and timestamp probes".to_string());
+        }
+        if request.multi {
+            return Err("only echo and timestamp probes collect several replies".to_string());
+        }
+        Ok(())
+    }
+
+    /// Sends the probe from the namespace of `ping`, `source` is the client
+    /// the ICMP engine answers for pending probes.
+    fn run(&self, ping: Arc<Ping>, source: SocketAddr, request: Request) -> ProbeFuture;
+}
+
+pub struct Probers {
+    probers: HashMap<Probe, Box<dyn Prober>>,
+}
+
+impl Probers {
+    /// All probe kinds the proxy knows.
+    pub fn new() -> Self {
+        let mut probers = Probers {
+            probers: HashMap::new(),
+        };
+        probers.register(Probe::Echo, EchoProbe);
+        probers.register(Probe::Udp, UdpProbe);
+        probers.register(Probe::Tcp, TcpProbe);
+        probers.register(Probe::Arp, ArpProbe);
+        probers.register(Probe::Ndp, NdpProbe);
+        probers.register(Probe::Coap, CoapProbe);
+        probers.register(Probe::Timestamp, TimestampProbe);
+        probers
+    }
+
+    pub fn register<P: Prober + 'static>(&mut self, probe: Probe, prober: P) {
+        self.probers.insert(probe, Box::new(prober));
+    }
+
+    pub fn get(&self, probe: Probe) -> Option<&dyn Prober> {
+        self.probers.get(&probe).map(|prober| prober.as_ref())
+    }
+
+    /// The probe types advertised to clients, in protocol order.
+    pub fn capabilities(&self) -> Vec<Probe> {
+        let mut probes: Vec<Probe> = self.probers.keys().copied().collect();
+        probes.sort_by_key(|probe| probe.as_u8());
+        probes
+    }
+}
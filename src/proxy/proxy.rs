@@ -1,70 +1,271 @@
-use std::{
-    error::Error,
-    net::{IpAddr, SocketAddr},
-    sync::Arc,
-};
+use std::{collections::HashMap, error::Error, fs::File, io, net::SocketAddr, sync::Arc};
 
-use tokio::net::UdpSocket;
+use tokio::{
+    net::UdpSocket,
+    time::{interval, Duration},
+};
 
-use buf_view::{BufView, BufViewMut};
+use ping_proxy::proto::{
+    Limits, Probe, ProtoError, Reply, Request, Status, INVALID_SEND, MAX_MESSAGE,
+};
 
+use crate::ether::{Ether, EtherConfig};
+use crate::limit::Limiter;
 use crate::ping::Ping;
+use crate::probe::Probers;
 
 #[derive(Debug)]
 pub struct ProxyInfo {
     pub target: SocketAddr,
-    pub seq: u32,
-    pub elapse: u32,
-    pub ttl: u8,
+    pub reply: Reply,
 }
 
-pub async fn server(addr: &str, port: u16) -> Result<(), Box<dyn Error>> {
-    let ping = Arc::new(Ping::new().await?);
+/// Where named network namespaces live, as `ip netns` creates them.
+const NETNS_DIR: &str = "/var/run/netns";
+
+pub async fn server(
+    addr: &str,
+    port: u16,
+    ether: Option<EtherConfig>,
+    netns: &[String],
+    limits: Limits,
+) -> Result<(), Box<dyn Error>> {
+    let ether = match ether {
+        Some(config) => Some(Ether::new(&config)?),
+        None => None,
+    };
+    let ping = Arc::new(Ping::new(ether)?);
+    let probers = Probers::new();
+    let mut limiter = Limiter::new(limits);
+
+    // every namespace gets an ICMP engine of its own, its replies reach
+    // the client through the shared socket like the proxy's own
+    let mut namespaces = HashMap::new();
+    for name in netns {
+        let path = format!("{}/{}", NETNS_DIR, name);
+        let file = File::open(&path).map_err(|err| format!("open {}: {}", path, err))?;
+        let engine = Ping::new_in(file)
+            .await
+            .map_err(|err| format!("namespace {}: {}", name, err))?;
+        namespaces.insert(name.clone(), Arc::new(engine));
+    }
 
     let host = format! {"{}:{}", addr, port};
     let socket = Arc::new(UdpSocket::bind(host).await?);
 
     println!("listen on port {port} ...");
 
-    ping_v4_run(&ping, &socket);
-    ping_v6_run(&ping, &socket);
+    engine_run(&ping, &socket);
+    for engine in namespaces.values() {
+        engine_run(engine, &socket);
+    }
 
-    let mut buf = [0u8; 1024];
+    let mut buf = [0u8; MAX_MESSAGE];
 
     loop {
         match socket.recv_from(&mut buf).await {
-            Ok((len, addr)) => proxy_rx(&ping, &buf, len, addr).await,
+            Ok((len, addr)) => {
+                let buf = &buf[..len];
+                proxy_rx(
+                    &ping,
+                    &namespaces,
+                    &probers,
+                    &mut limiter,
+                    &socket,
+                    buf,
+                    addr,
+                )
+                .await
+            }
             Err(err) => println!("proxy rx error: {}", err),
         }
     }
 }
 
-async fn proxy_rx(ping: &Ping, buf: &[u8], len: usize, addr: SocketAddr) {
-    let mut buf = BufView::wrap_with(buf, 0, len);
-    let seq = buf.read_u32();
-    let pkt_len = buf.read_u16() as usize;
-    let host_len = buf.read_u8() as usize;
+async fn proxy_rx(
+    ping: &Arc<Ping>,
+    namespaces: &HashMap<String, Arc<Ping>>,
+    probers: &Probers,
+    limiter: &mut Limiter,
+    socket: &Arc<UdpSocket>,
+    buf: &[u8],
+    addr: SocketAddr,
+) {
+    let request = match Request::decode(buf).and_then(|request| {
+        request.validate()?;
+        Ok(request)
+    }) {
+        Ok(request) => request,
+        Err(err) => return reject(probers, socket, buf, addr, err).await,
+    };
+
+    if request.probe == Probe::Capabilities {
+        let mut reply = Reply::new(request.seq, 0, 0);
+        reply.probes = probers.capabilities();
+        reply.limits = Some(limiter.limits());
+        let info = ProxyInfo {
+            target: addr,
+            reply,
+        };
+        return ping_rx(socket, &info).await;
+    }
+
+    // the client's probes in every namespace count against its limit
+    let in_flight = std::iter::once(ping)
+        .chain(namespaces.values())
+        .map(|engine| engine.in_flight(&addr))
+        .sum();
+    let ping = match &request.netns {
+        Some(name) => match namespaces.get(name) {
+            Some(engine) => engine,
+            None => {
+                let error = format!("unknown namespace {}", name);
+                return probe_error(socket, addr, request.seq, error).await;
+            }
+        },
+        None => ping,
+    };
+
+    let prober = match probers.get(request.probe) {
+        Some(prober) => prober,
+        None => {
+            let error = format!("{} probes are not supported", request.probe.name());
+            return probe_error(socket, addr, request.seq, error).await;
+        }
+    };
+    if let Err(error) = prober.check(ping, &request) {
+        return probe_error(socket, addr, request.seq, error).await;
+    }
 
-    if host_len + 7 != len {
+    // only a probe the proxy would carry out spends a token, the limits
+    // come along so the client can slow down
+    if let Err(error) = limiter.admit(addr, in_flight) {
+        let mut reply = Reply::new(request.seq, 0, 0);
+        reply.status = Status::Invalid;
+        reply.error = Some(error);
+        reply.limits = Some(limiter.limits());
+        let info = ProxyInfo {
+            target: addr,
+            reply,
+        };
+        return ping_rx(socket, &info).await;
+    }
+
+    let seq = request.seq;
+    let ipv4 = request.host.is_ipv4();
+    let probe = prober.run(ping.clone(), addr, request);
+    let socket = socket.clone();
+    tokio::spawn(async move {
+        match probe.await {
+            Ok(Some(reply)) => {
+                let info = ProxyInfo {
+                    target: addr,
+                    reply,
+                };
+                ping_rx(&socket, &info).await;
+            }
+            Ok(None) => {}
+            Err(err) => match unreachable_code(&err, ipv4) {
+                Some(code) => {
+                    let mut reply = Reply::new(seq, 0, 0);
+                    reply.status = Status::Unreachable;
+                    reply.code = code;
+                    let info = ProxyInfo {
+                        target: addr,
+                        reply,
+                    };
+                    ping_rx(&socket, &info).await;
+                }
+                None => probe_failed(&socket, addr, seq, err.to_string()).await,
+            },
+        }
+    });
+}
+
+/// Tells the client why its request was refused, when there is at least
+/// a seq to answer to. An unknown probe type comes with the ones the proxy
+/// does support.
+async fn reject(
+    probers: &Probers,
+    socket: &UdpSocket,
+    buf: &[u8],
+    addr: SocketAddr,
+    err: ProtoError,
+) {
+    if buf.len() < 4 {
         return;
     }
 
-    let host = if host_len == 4 {
-        let mut v4 = [0u8; 4];
-        buf.read_bytes(&mut v4);
-        IpAddr::from(v4)
-    } else {
-        let mut v6 = [0u8; 16];
-        buf.read_bytes(&mut v6);
-        IpAddr::from(v6)
+    let seq = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let mut reply = Reply::new(seq, 0, 0);
+    reply.status = Status::Invalid;
+    reply.error = Some(err.to_string());
+    if let ProtoError::Probe = err {
+        reply.probes = probers.capabilities();
+    }
+    let info = ProxyInfo {
+        target: addr,
+        reply,
     };
+    ping_rx(socket, &info).await;
+}
 
-    let target = SocketAddr::new(host, 0);
-    if let Err(err) = ping.send_to(&addr, &target, seq, pkt_len).await {
-        println!("ping {:?} error: {}", target, err);
+/// The ICMP code of a send the proxy's own stack found no way to the host
+/// for, the host is unreachable as if a router had said so. A neighbour
+/// that never answered in ether mode is one of them.
+fn unreachable_code(err: &io::Error, ipv4: bool) -> Option<u8> {
+    match (err.kind(), ipv4) {
+        (io::ErrorKind::NetworkUnreachable, _) => Some(0),
+        (io::ErrorKind::HostUnreachable, true) => Some(1),
+        (io::ErrorKind::HostUnreachable, false) => Some(3),
+        _ => None,
     }
 }
 
+/// Answers a request the proxy will not carry out with the reason.
+async fn probe_error(socket: &UdpSocket, addr: SocketAddr, seq: u32, error: String) {
+    probe_invalid(socket, addr, seq, 0, error).await;
+}
+
+/// Answers a probe the proxy took but failed to send, the next one may
+/// well go out.
+async fn probe_failed(socket: &UdpSocket, addr: SocketAddr, seq: u32, error: String) {
+    probe_invalid(socket, addr, seq, INVALID_SEND, error).await;
+}
+
+async fn probe_invalid(socket: &UdpSocket, addr: SocketAddr, seq: u32, code: u8, error: String) {
+    let mut reply = Reply::new(seq, 0, 0);
+    reply.status = Status::Invalid;
+    reply.code = code;
+    reply.error = Some(error);
+    let info = ProxyInfo {
+        target: addr,
+        reply,
+    };
+    ping_rx(socket, &info).await;
+}
+
+/// Starts the receive and expire tasks of an ICMP engine.
+fn engine_run(ping: &Arc<Ping>, socket: &Arc<UdpSocket>) {
+    ping_v4_run(ping, socket);
+    ping_v6_run(ping, socket);
+    if ping.ether_enabled() {
+        ping_ether_run(ping, socket);
+    }
+    expire_run(ping);
+}
+
+fn expire_run(ping: &Arc<Ping>) {
+    let ping = ping.clone();
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            ping.expire();
+        }
+    });
+}
+
 fn ping_v4_run(ping: &Arc<Ping>, socket: &Arc<UdpSocket>) {
     let ping = ping.clone();
     let socket = socket.clone();
@@ -77,6 +278,12 @@ fn ping_v6_run(ping: &Arc<Ping>, socket: &Arc<UdpSocket>) {
     tokio::spawn(async move { ping_v6_rx(&ping, &socket).await });
 }
 
+fn ping_ether_run(ping: &Arc<Ping>, socket: &Arc<UdpSocket>) {
+    let ping = ping.clone();
+    let socket = socket.clone();
+    tokio::spawn(async move { ping_ether_rx(&ping, &socket).await });
+}
+
 async fn ping_v4_rx(ping: &Arc<Ping>, socket: &Arc<UdpSocket>) {
     loop {
         if let Some(info) = ping.recv_from_v4().await {
@@ -93,18 +300,37 @@ async fn ping_v6_rx(ping: &Arc<Ping>, socket: &UdpSocket) {
     }
 }
 
+async fn ping_ether_rx(ping: &Arc<Ping>, socket: &UdpSocket) {
+    loop {
+        if let Some(info) = ping.recv_from_ether().await {
+            ping_rx(socket, &info).await;
+        }
+    }
+}
+
 async fn ping_rx(socket: &UdpSocket, info: &ProxyInfo) {
-    let mut buf = [0u8; 32];
-    let len = build_proxy_respone(&mut buf, info);
+    let mut buf = [0u8; MAX_MESSAGE];
+    let len = info.reply.encode(&mut buf);
     if let Err(err) = socket.send_to(&buf[..len], &info.target).await {
         println!("proxy response error: {}", err);
     }
 }
 
-fn build_proxy_respone(buf: &mut [u8], info: &ProxyInfo) -> usize {
-    let mut buf = BufViewMut::wrap(buf);
-    buf.write_u32(info.seq);
-    buf.write_u32(info.elapse);
-    buf.write_u8(info.ttl);
-    buf.remaining()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreachable_codes() {
+        let err = io::Error::from_raw_os_error(libc::EHOSTUNREACH);
+        assert_eq!(unreachable_code(&err, true), Some(1));
+        assert_eq!(unreachable_code(&err, false), Some(3));
+        let err = io::Error::from_raw_os_error(libc::ENETUNREACH);
+        assert_eq!(unreachable_code(&err, true), Some(0));
+        // a neighbour that never answered
+        let err = io::Error::new(io::ErrorKind::HostUnreachable, "no link layer address");
+        assert_eq!(unreachable_code(&err, true), Some(1));
+        let err = io::Error::from_raw_os_error(libc::ENOBUFS);
+        assert_eq!(unreachable_code(&err, true), None);
+    }
 }
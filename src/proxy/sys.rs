@@ -0,0 +1,917 @@
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    fs::File,
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+    ptr,
+    sync::{mpsc, Mutex},
+};
+use tokio::{io::Interest, net::UdpSocket, sync::oneshot};
+
+//
+// sendmsg/recvmsg wrappers, the raw sockets are shared by every client,
+// so per packet options go through ancillary data instead of setsockopt.
+//
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SendOptions {
+    pub ttl: Option<u8>,
+    pub tos: Option<u8>,
+}
+
+#[derive(Debug)]
+pub struct RecvInfo {
+    pub len: usize,
+    pub from: SocketAddr,
+    pub hop_limit: Option<u8>,
+    pub tos: Option<u8>,
+    pub stamp: Option<Timestamp>,
+    // the number of the packet a transmit timestamp is for, error queue only
+    pub tx_key: Option<u32>,
+}
+
+/// Kernel timestamps of a packet in nanoseconds of CLOCK_REALTIME, the
+/// hardware one is the NIC clock and only set when the interface has
+/// hardware timestamping turned on.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timestamp {
+    pub software: Option<u64>,
+    pub hardware: Option<u64>,
+}
+
+/// An interface with its link layer and IP addresses, as getifaddrs lists it.
+#[derive(Debug, Clone, Default)]
+pub struct Interface {
+    pub name: String,
+    pub index: u32,
+    pub mac: Option<[u8; 6]>,
+    /// address and prefix length
+    pub addrs: Vec<(IpAddr, u8)>,
+}
+
+impl Interface {
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.addrs.iter().find_map(|(addr, _)| match addr {
+            IpAddr::V4(addr) => Some(*addr),
+            IpAddr::V6(_) => None,
+        })
+    }
+
+    /// True when `target` is on one of the interface's subnets.
+    pub fn on_link(&self, target: &IpAddr) -> bool {
+        self.addrs
+            .iter()
+            .any(|(addr, prefix)| match (addr, target) {
+                (IpAddr::V4(addr), IpAddr::V4(target)) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                    u32::from(*addr) & mask == u32::from(*target) & mask
+                }
+                (IpAddr::V6(addr), IpAddr::V6(target)) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                    u128::from(*addr) & mask == u128::from(*target) & mask
+                }
+                _ => false,
+            })
+    }
+
+    /// True when `target` is the limited broadcast or the broadcast
+    /// address of one of the interface's subnets.
+    pub fn broadcast(&self, target: &Ipv4Addr) -> bool {
+        target.is_broadcast()
+            || self.addrs.iter().any(|(addr, prefix)| match addr {
+                IpAddr::V4(addr) if *prefix < 31 => {
+                    let host = u32::MAX >> *prefix as u32;
+                    u32::from(*addr) | host == u32::from(*target)
+                }
+                _ => false,
+            })
+    }
+}
+
+// not in every libc release, the values are the same on all asm-generic
+// architectures
+const SO_TIMESTAMPING: libc::c_int = 37;
+const SCM_TIMESTAMPING: libc::c_int = SO_TIMESTAMPING;
+
+pub async fn send_to(
+    socket: &UdpSocket,
+    buf: &[u8],
+    target: &SocketAddr,
+    opts: &SendOptions,
+) -> io::Result<usize> {
+    socket
+        .async_io(Interest::WRITABLE, || {
+            sendmsg(socket.as_raw_fd(), buf, target, opts)
+        })
+        .await
+}
+
+pub async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<RecvInfo> {
+    socket
+        .async_io(Interest::READABLE, || recvmsg(socket.as_raw_fd(), buf))
+        .await
+}
+
+///
+/// The packets sent on a timestamping socket waiting for their transmit
+/// timestamp. With `SOF_TIMESTAMPING_OPT_ID` the kernel numbers the packets
+/// of a socket from 0 and a timestamp comes back with the number alone, it
+/// is counted the same way here under the lock the send is made in.
+///
+#[derive(Debug)]
+pub struct TxStamps<K> {
+    // the next number and what the numbers in flight were sent for
+    sent: Mutex<(u32, HashMap<u32, K>)>,
+}
+
+impl<K: Copy> TxStamps<K> {
+    pub fn new() -> Self {
+        TxStamps {
+            sent: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Sends like `send_to` and keeps the packet's number for `key`. A send
+    /// that fails may have taken a number or not, the count starts over.
+    pub async fn send_to(
+        &self,
+        socket: &UdpSocket,
+        buf: &[u8],
+        target: &SocketAddr,
+        opts: &SendOptions,
+        key: K,
+    ) -> io::Result<usize> {
+        socket
+            .async_io(Interest::WRITABLE, || {
+                let mut sent = self.sent.lock().unwrap();
+                match sendmsg(socket.as_raw_fd(), buf, target, opts) {
+                    Ok(len) => {
+                        let number = sent.0;
+                        sent.0 = number.wrapping_add(1);
+                        sent.1.insert(number, key);
+                        Ok(len)
+                    }
+                    Err(err) => {
+                        drain_errqueue(socket.as_raw_fd());
+                        if restart_tx_keys(socket).is_ok() {
+                            *sent = (0, HashMap::new());
+                        }
+                        Err(err)
+                    }
+                }
+            })
+            .await
+    }
+
+    /// Drains the transmit timestamps the kernel looped back on the error
+    /// queue, each with the key its packet was sent for.
+    pub fn recv(&self, socket: &UdpSocket) -> Vec<(K, Timestamp)> {
+        let mut stamps = Vec::new();
+        let mut buf = [0u8; 64];
+        let mut sent = self.sent.lock().unwrap();
+        while let Ok(info) = recvmsg_flags(
+            socket.as_raw_fd(),
+            &mut buf,
+            libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+        ) {
+            let key = info.tx_key.and_then(|number| sent.1.remove(&number));
+            if let (Some(key), Some(stamp)) = (key, info.stamp) {
+                stamps.push((key, stamp));
+            }
+        }
+        stamps
+    }
+
+    /// Forgets the packets whose key `keep` turns down, a packet the kernel
+    /// took no timestamp for would wait forever.
+    pub fn retain<F: FnMut(&K) -> bool>(&self, mut keep: F) {
+        self.sent.lock().unwrap().1.retain(|_, key| keep(key));
+    }
+}
+
+fn drain_errqueue(fd: RawFd) {
+    let mut buf = [0u8; 64];
+    while recvmsg_flags(fd, &mut buf, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT).is_ok() {}
+}
+
+/// The kernel numbers the packets from 0 again once `SOF_TIMESTAMPING_OPT_ID`
+/// is turned back on.
+fn restart_tx_keys<S: AsRawFd>(socket: &S) -> io::Result<()> {
+    let flags = TIMESTAMPING & !libc::SOF_TIMESTAMPING_OPT_ID;
+    setsockopt(
+        socket.as_raw_fd(),
+        libc::SOL_SOCKET,
+        SO_TIMESTAMPING,
+        flags as libc::c_int,
+    )?;
+    set_timestamping(socket)
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A thread that joined a network namespace for good, the sockets of the
+/// probes from that namespace are opened on it. The sockets are registered
+/// with the runtime that started the thread.
+#[derive(Debug)]
+pub struct Netns {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Netns {
+    pub async fn new(netns: File) -> io::Result<Netns> {
+        let runtime = tokio::runtime::Handle::current();
+        let (jobs, queue) = mpsc::channel::<Job>();
+        std::thread::Builder::new()
+            .name("netns".to_string())
+            .spawn(move || {
+                let _runtime = runtime.enter();
+                // ends once the engine and its sender are gone, a job that
+                // panics takes only its own probe down
+                for job in queue {
+                    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+                }
+            })?;
+
+        let thread = Netns { jobs };
+        thread
+            .run(move || {
+                if unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            })
+            .await?;
+        Ok(thread)
+    }
+
+    /// Runs `f` on the namespace's thread.
+    pub async fn run<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        let (done, result) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move || {
+                let _ = done.send(f());
+            }))
+            .map_err(|_| io::Error::other("namespace thread is gone"))?;
+        result
+            .await
+            .unwrap_or_else(|_| Err(io::Error::other("namespace thread panicked")))
+    }
+}
+
+/// A random number from the kernel, the pid if there is none.
+pub fn random_u16() -> u16 {
+    let mut value = [0u8; 2];
+    let ret = unsafe { libc::getrandom(value.as_mut_ptr() as *mut libc::c_void, value.len(), 0) };
+    if ret != value.len() as isize {
+        return std::process::id() as u16;
+    }
+    u16::from_ne_bytes(value)
+}
+
+const TIMESTAMPING: libc::c_uint = libc::SOF_TIMESTAMPING_TX_SOFTWARE
+    | libc::SOF_TIMESTAMPING_RX_SOFTWARE
+    | libc::SOF_TIMESTAMPING_TX_HARDWARE
+    | libc::SOF_TIMESTAMPING_RX_HARDWARE
+    | libc::SOF_TIMESTAMPING_SOFTWARE
+    | libc::SOF_TIMESTAMPING_RAW_HARDWARE
+    | libc::SOF_TIMESTAMPING_OPT_ID
+    | libc::SOF_TIMESTAMPING_OPT_TSONLY;
+
+/// Asks for software and hardware timestamps of sent and received packets,
+/// sent ones come back on the error queue with the packet's number instead
+/// of a copy of it, see `TxStamps`.
+pub fn set_timestamping<S: AsRawFd>(socket: &S) -> io::Result<()> {
+    setsockopt(
+        socket.as_raw_fd(),
+        libc::SOL_SOCKET,
+        SO_TIMESTAMPING,
+        TIMESTAMPING as libc::c_int,
+    )
+}
+
+pub fn set_recv_hoplimit(socket: &UdpSocket) -> io::Result<()> {
+    setsockopt(
+        socket.as_raw_fd(),
+        libc::IPPROTO_IPV6,
+        libc::IPV6_RECVHOPLIMIT,
+        1 as libc::c_int,
+    )
+}
+
+/// Asks for the TOS or traffic class of received packets as ancillary data.
+pub fn set_recv_tos<S: AsRawFd>(socket: &S, ipv4: bool) -> io::Result<()> {
+    if ipv4 {
+        setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_RECVTOS,
+            1 as libc::c_int,
+        )
+    } else {
+        setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_RECVTCLASS,
+            1 as libc::c_int,
+        )
+    }
+}
+
+/// Sets the TOS or traffic class on a socket owned by a single probe.
+pub fn set_tos<S: AsRawFd>(socket: &S, ipv4: bool, tos: u8) -> io::Result<()> {
+    if ipv4 {
+        SockRef::from(socket).set_tos(tos as u32)
+    } else {
+        setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            tos as libc::c_int,
+        )
+    }
+}
+
+/// Sets the TTL or hop limit on a socket owned by a single probe.
+pub fn set_hop_limit<S: AsRawFd>(socket: &S, ipv4: bool, ttl: u8) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    if ipv4 {
+        socket.set_ttl(ttl as u32)
+    } else {
+        socket.set_unicast_hops_v6(ttl as u32)
+    }
+}
+
+/// Sets DF on every packet and ignores the cached path MTU, so a probe
+/// larger than the path is answered by "fragmentation needed" or
+/// "packet too big" instead of being fragmented.
+pub fn set_dont_fragment<S: AsRawFd>(socket: &S, ipv4: bool) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    if ipv4 {
+        setsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        )
+    } else {
+        setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        )?;
+        setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_DONTFRAG,
+            1 as libc::c_int,
+        )
+    }
+}
+
+/// Filters out every ICMP type on a raw socket that is only used to send,
+/// each raw socket gets its own copy of all incoming ICMP.
+pub fn block_icmp<S: AsRawFd>(socket: &S, ipv4: bool) -> io::Result<()> {
+    const ICMP_FILTER: libc::c_int = 1;
+    const ICMP6_FILTER: libc::c_int = 1;
+
+    if ipv4 {
+        setsockopt(socket.as_raw_fd(), libc::SOL_RAW, ICMP_FILTER, u32::MAX)
+    } else {
+        setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_ICMPV6,
+            ICMP6_FILTER,
+            [u32::MAX; 8],
+        )
+    }
+}
+
+/// Lets only the given ICMPv6 types through to a raw socket.
+pub fn pass_icmp6<S: AsRawFd>(socket: &S, types: &[u8]) -> io::Result<()> {
+    const ICMP6_FILTER: libc::c_int = 1;
+
+    // a set bit blocks the type
+    let mut filter = [u32::MAX; 8];
+    for icmp_type in types {
+        filter[(*icmp_type >> 5) as usize] &= !(1 << (icmp_type & 31));
+    }
+    setsockopt(
+        socket.as_raw_fd(),
+        libc::IPPROTO_ICMPV6,
+        ICMP6_FILTER,
+        filter,
+    )
+}
+
+/// The MTU of the route to `target` as the kernel knows it.
+pub fn path_mtu(target: IpAddr) -> io::Result<u32> {
+    let bind = match target {
+        IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = std::net::UdpSocket::bind(bind)?;
+    socket.connect(SocketAddr::new(target, 9))?;
+
+    let (level, name) = if target.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_MTU)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU)
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut mtu as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(mtu as u32)
+}
+
+pub fn interfaces() -> io::Result<Vec<Interface>> {
+    let mut ifaddrs: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut ifa = ifaddrs;
+    while !ifa.is_null() {
+        let entry = unsafe { &*ifa };
+        ifa = entry.ifa_next;
+        if entry.ifa_addr.is_null() {
+            continue;
+        }
+
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        let position = match interfaces.iter().position(|iface| iface.name == name) {
+            Some(position) => position,
+            None => {
+                let index = unsafe { libc::if_nametoindex(entry.ifa_name) };
+                interfaces.push(Interface {
+                    name,
+                    index,
+                    ..Default::default()
+                });
+                interfaces.len() - 1
+            }
+        };
+        let iface = &mut interfaces[position];
+
+        let family = unsafe { (*entry.ifa_addr).sa_family } as libc::c_int;
+        match family {
+            libc::AF_PACKET => {
+                let ll = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_ll) };
+                if ll.sll_halen == 6 {
+                    let mut mac = [0u8; 6];
+                    mac.copy_from_slice(&ll.sll_addr[..6]);
+                    iface.mac = Some(mac);
+                }
+            }
+            libc::AF_INET => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                let addr = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                let prefix = if entry.ifa_netmask.is_null() {
+                    32
+                } else {
+                    let mask = unsafe { &*(entry.ifa_netmask as *const libc::sockaddr_in) };
+                    u32::from_be(mask.sin_addr.s_addr).count_ones() as u8
+                };
+                iface.addrs.push((IpAddr::V4(addr), prefix));
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                let addr = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                let prefix = if entry.ifa_netmask.is_null() {
+                    128
+                } else {
+                    let mask = unsafe { &*(entry.ifa_netmask as *const libc::sockaddr_in6) };
+                    u128::from_be_bytes(mask.sin6_addr.s6_addr).count_ones() as u8
+                };
+                iface.addrs.push((IpAddr::V6(addr), prefix));
+            }
+            _ => {}
+        }
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(interfaces)
+}
+
+/// The interface called `name`, or without a name the one `target` is on
+/// link from.
+pub fn interface_for(target: &IpAddr, name: Option<&str>) -> io::Result<Interface> {
+    let interfaces = interfaces()?;
+    let found = match name {
+        Some(name) => interfaces.into_iter().find(|iface| iface.name == name),
+        None => interfaces
+            .into_iter()
+            .find(|iface| iface.mac.is_some() && iface.on_link(target)),
+    };
+
+    found.ok_or_else(|| {
+        let msg = match name {
+            Some(name) => format!("no interface {}", name),
+            None => format!("no interface on link with {}", target),
+        };
+        io::Error::new(io::ErrorKind::NotFound, msg)
+    })
+}
+
+/// A cooked AF_PACKET socket for one ethertype on one interface, the
+/// kernel builds the link layer header.
+pub fn packet_socket(index: u32, protocol: u16) -> io::Result<UdpSocket> {
+    bound_packet_socket(index, protocol, Type::DGRAM)
+}
+
+/// An AF_PACKET socket that sends and receives whole frames, link layer
+/// header included.
+pub fn raw_packet_socket(index: u32, protocol: u16) -> io::Result<UdpSocket> {
+    bound_packet_socket(index, protocol, Type::RAW)
+}
+
+fn bound_packet_socket(index: u32, protocol: u16, typ: Type) -> io::Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::PACKET,
+        typ,
+        Some(Protocol::from(protocol.to_be() as libc::c_int)),
+    )?;
+    socket.bind(&link_addr(index, protocol, [0u8; 6]))?;
+    socket.set_nonblocking(true)?;
+
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(socket.into_raw_fd()) };
+    UdpSocket::from_std(socket)
+}
+
+/// Has the interface accept frames to `mac` while the packet socket is
+/// open, the kernel falls back to promiscuous mode when the driver has no
+/// unicast filter.
+pub fn add_unicast_mac<S: AsRawFd>(socket: &S, index: u32, mac: &[u8; 6]) -> io::Result<()> {
+    add_membership(socket, index, libc::PACKET_MR_UNICAST, mac)
+}
+
+/// Joins the link layer multicast group `mac` while the packet socket is
+/// open.
+pub fn add_multicast_mac<S: AsRawFd>(socket: &S, index: u32, mac: &[u8; 6]) -> io::Result<()> {
+    add_membership(socket, index, libc::PACKET_MR_MULTICAST, mac)
+}
+
+fn add_membership<S: AsRawFd>(
+    socket: &S,
+    index: u32,
+    typ: libc::c_int,
+    mac: &[u8; 6],
+) -> io::Result<()> {
+    let mut mreq: libc::packet_mreq = unsafe { mem::zeroed() };
+    mreq.mr_ifindex = index as libc::c_int;
+    mreq.mr_type = typ as libc::c_ushort;
+    mreq.mr_alen = 6;
+    mreq.mr_address[..6].copy_from_slice(mac);
+    setsockopt(
+        socket.as_raw_fd(),
+        libc::SOL_PACKET,
+        libc::PACKET_ADD_MEMBERSHIP,
+        mreq,
+    )
+}
+
+/// Has a packet socket report the 802.1Q tag the driver took off a frame.
+pub fn set_packet_auxdata<S: AsRawFd>(socket: &S) -> io::Result<()> {
+    setsockopt(
+        socket.as_raw_fd(),
+        libc::SOL_PACKET,
+        libc::PACKET_AUXDATA,
+        1 as libc::c_int,
+    )
+}
+
+/// Receives a frame on a packet socket with the tag control information
+/// of the VLAN tag the driver stripped, if it did.
+pub async fn packet_recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Option<u16>)> {
+    socket
+        .async_io(Interest::READABLE, || {
+            let mut iov = libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+            let mut control = [0u64; 16];
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = mem::size_of_val(&control) as _;
+
+            let ret = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut tci = None;
+            unsafe {
+                let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+                while !cmsg.is_null() {
+                    if (*cmsg).cmsg_level == libc::SOL_PACKET
+                        && (*cmsg).cmsg_type == libc::PACKET_AUXDATA
+                    {
+                        let aux = ptr::read_unaligned(
+                            libc::CMSG_DATA(cmsg) as *const libc::tpacket_auxdata
+                        );
+                        if aux.tp_status & libc::TP_STATUS_VLAN_VALID != 0 {
+                            tci = Some(aux.tp_vlan_tci);
+                        }
+                    }
+                    cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                }
+            }
+            Ok((ret as usize, tci))
+        })
+        .await
+}
+
+/// The address frames to `target` through interface `name` go to after
+/// the kernel routing table, the target itself when it is on link, `None`
+/// when no route through the interface covers it.
+pub fn route_next_hop(name: &str, target: &IpAddr) -> io::Result<Option<IpAddr>> {
+    // prefix length, metric and gateway of the best route so far
+    let mut best: Option<(u32, u32, Option<IpAddr>)> = None;
+    let mut consider = |prefix: u32, metric: u32, gateway: Option<IpAddr>| {
+        let better = match best {
+            Some((best_prefix, best_metric, _)) => {
+                prefix > best_prefix || (prefix == best_prefix && metric < best_metric)
+            }
+            None => true,
+        };
+        if better {
+            best = Some((prefix, metric, gateway));
+        }
+    };
+
+    match target {
+        IpAddr::V4(target) => {
+            //
+            // | interface | dest | gateway | flags | refcnt | use | metric | mask |,
+            // addresses in native byte order hex
+            //
+            let table = std::fs::read_to_string("/proc/net/route")?;
+            let target = u32::from_ne_bytes(target.octets());
+            for line in table.lines().skip(1) {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() < 8 || fields[0] != name {
+                    continue;
+                }
+                let hex = |field: &str| u32::from_str_radix(field, 16).ok();
+                if let (Some(dest), Some(gateway), Some(metric), Some(mask)) = (
+                    hex(fields[1]),
+                    hex(fields[2]),
+                    fields[6].parse().ok(),
+                    hex(fields[7]),
+                ) {
+                    if target & mask == dest {
+                        let gateway = Some(gateway)
+                            .filter(|gateway| *gateway != 0)
+                            .map(|gateway| IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())));
+                        consider(mask.count_ones(), metric, gateway);
+                    }
+                }
+            }
+        }
+        IpAddr::V6(target) => {
+            //
+            // | dest | dest prefix | source | source prefix | next hop | metric |
+            // | refcnt | use | flags | interface |, addresses in 32 hex digits
+            //
+            let table = std::fs::read_to_string("/proc/net/ipv6_route")?;
+            let target = u128::from_be_bytes(target.octets());
+            for line in table.lines() {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() < 10 || fields[9] != name {
+                    continue;
+                }
+                let hex = |field: &str| u128::from_str_radix(field, 16).ok();
+                let prefix = u32::from_str_radix(fields[1], 16).ok();
+                let metric = u32::from_str_radix(fields[5], 16).ok();
+                if let (Some(dest), Some(prefix), Some(gateway), Some(metric)) =
+                    (hex(fields[0]), prefix, hex(fields[4]), metric)
+                {
+                    let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                    if target & mask == dest {
+                        let gateway = Some(gateway)
+                            .filter(|gateway| *gateway != 0)
+                            .map(|gateway| IpAddr::V6(Ipv6Addr::from(gateway)));
+                        consider(prefix, metric, gateway);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(best.map(|(_, _, gateway)| gateway.unwrap_or(*target)))
+}
+
+/// Sends `buf` to the link layer address `dest` on a packet socket.
+pub async fn packet_send(
+    socket: &UdpSocket,
+    buf: &[u8],
+    index: u32,
+    protocol: u16,
+    dest: [u8; 6],
+) -> io::Result<usize> {
+    let addr = link_addr(index, protocol, dest);
+    socket
+        .async_io(Interest::WRITABLE, || {
+            let ret = unsafe {
+                libc::sendto(
+                    socket.as_raw_fd(),
+                    buf.as_ptr() as *const libc::c_void,
+                    buf.len(),
+                    0,
+                    addr.as_ptr(),
+                    addr.len(),
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(ret as usize)
+        })
+        .await
+}
+
+fn link_addr(index: u32, protocol: u16, dest: [u8; 6]) -> SockAddr {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let ll =
+        unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_ll) };
+    ll.sll_family = libc::AF_PACKET as libc::sa_family_t;
+    ll.sll_protocol = protocol.to_be();
+    ll.sll_ifindex = index as libc::c_int;
+    ll.sll_halen = 6;
+    ll.sll_addr[..6].copy_from_slice(&dest);
+
+    let len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+    unsafe { SockAddr::new(storage, len) }
+}
+
+fn setsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn sendmsg(fd: RawFd, buf: &[u8], target: &SocketAddr, opts: &SendOptions) -> io::Result<usize> {
+    let addr = SockAddr::from(*target);
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut cmsgs = Vec::new();
+    if let Some(ttl) = opts.ttl {
+        if target.is_ipv4() {
+            cmsgs.push((libc::IPPROTO_IP, libc::IP_TTL, ttl as libc::c_int));
+        } else {
+            cmsgs.push((libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT, ttl as libc::c_int));
+        }
+    }
+    if let Some(tos) = opts.tos {
+        if target.is_ipv4() {
+            cmsgs.push((libc::IPPROTO_IP, libc::IP_TOS, tos as libc::c_int));
+        } else {
+            cmsgs.push((libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos as libc::c_int));
+        }
+    }
+
+    let mut control = [0u64; 16];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = addr.as_ptr() as *mut libc::c_void;
+    msg.msg_namelen = addr.len();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    if !cmsgs.is_empty() {
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as u32) } as usize;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = (space * cmsgs.len()) as _;
+
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            for (level, name, value) in cmsgs {
+                (*cmsg).cmsg_level = level;
+                (*cmsg).cmsg_type = name;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::c_int>() as u32) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, value);
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+    }
+
+    let ret = unsafe { libc::sendmsg(fd, &msg, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+fn recvmsg(fd: RawFd, buf: &mut [u8]) -> io::Result<RecvInfo> {
+    recvmsg_flags(fd, buf, 0)
+}
+
+fn recvmsg_flags(fd: RawFd, buf: &mut [u8], flags: libc::c_int) -> io::Result<RecvInfo> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut control = [0u64; 32];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let ret = unsafe { libc::recvmsg(fd, &mut msg, flags) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // the error queue has no source address for looped back packets
+    let from = unsafe { SockAddr::new(storage, msg.msg_namelen) }
+        .as_socket()
+        .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+
+    let mut info = RecvInfo {
+        len: ret as usize,
+        from,
+        hop_limit: None,
+        tos: None,
+        stamp: None,
+        tx_key: None,
+    };
+
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let level = (*cmsg).cmsg_level;
+            let name = (*cmsg).cmsg_type;
+            if level == libc::IPPROTO_IPV6 && name == libc::IPV6_HOPLIMIT {
+                let value = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                info.hop_limit = Some(value as u8);
+            } else if level == libc::IPPROTO_IPV6 && name == libc::IPV6_TCLASS {
+                let value = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                info.tos = Some(value as u8);
+            } else if level == libc::IPPROTO_IP && name == libc::IP_TOS {
+                info.tos = Some(*libc::CMSG_DATA(cmsg));
+            } else if level == libc::SOL_SOCKET && name == SCM_TIMESTAMPING {
+                // software, deprecated, raw hardware
+                let ts = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const [libc::timespec; 3]);
+                let nanos = |ts: &libc::timespec| {
+                    let nanos = ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64;
+                    Some(nanos).filter(|nanos| *nanos != 0)
+                };
+                info.stamp = Some(Timestamp {
+                    software: nanos(&ts[0]),
+                    hardware: nanos(&ts[2]),
+                });
+            } else if (level == libc::IPPROTO_IP && name == libc::IP_RECVERR)
+                || (level == libc::IPPROTO_IPV6 && name == libc::IPV6_RECVERR)
+            {
+                let err =
+                    ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err);
+                if err.ee_origin == libc::SO_EE_ORIGIN_TIMESTAMPING {
+                    info.tx_key = Some(err.ee_data);
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok(info)
+}
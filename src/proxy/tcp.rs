@@ -0,0 +1,102 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    net::TcpSocket,
+    time::{timeout, Duration},
+};
+
+use ping_proxy::proto::{Reply, Request, Status};
+
+use crate::ping::{Ping, ProbeKey};
+use crate::probe::{ProbeFuture, Prober};
+use crate::sys;
+
+pub const DEFAULT_PORT: u16 = 80;
+
+/// Time left to the ICMP engine to answer the error that failed a connect.
+const ICMP_GRACE: Duration = Duration::from_millis(100);
+
+///
+/// A TCP probe is a plain connect(), the SYN-ACK completes it and a RST
+/// refuses it. ICMP errors quote the TCP header and are matched by the
+/// local port in the ICMP engine.
+///
+pub struct TcpProbe;
+
+impl Prober for TcpProbe {
+    fn run(&self, ping: Arc<Ping>, source: SocketAddr, request: Request) -> ProbeFuture {
+        Box::pin(async move { tcp_probe(&ping, &source, &request).await })
+    }
+}
+
+async fn tcp_probe(
+    ping: &Ping,
+    source: &SocketAddr,
+    request: &Request,
+) -> io::Result<Option<Reply>> {
+    let ipv4 = request.host.is_ipv4();
+    let (tcp, bind) = ping
+        .in_netns(move || {
+            Ok(if ipv4 {
+                let bind = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+                (TcpSocket::new_v4()?, bind)
+            } else {
+                let bind = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+                (TcpSocket::new_v6()?, bind)
+            })
+        })
+        .await?;
+    if let Some(ttl) = request.ttl {
+        sys::set_hop_limit(&tcp, ipv4, ttl)?;
+    }
+    if let Some(tos) = request.tos {
+        sys::set_tos(&tcp, ipv4, tos)?;
+    }
+    tcp.bind(bind)?;
+
+    let port = if request.port == 0 {
+        DEFAULT_PORT
+    } else {
+        request.port
+    };
+    let target = SocketAddr::new(request.host, port);
+    let key = ProbeKey::Tcp(tcp.local_addr()?.port());
+
+    // the ICMP engine answers time exceeded and unreachable itself, with
+    // the code the router sent
+    let claimed = ping.register(key, source, request.seq, request.timeout);
+    let wait = Duration::from_millis(request.timeout as u64);
+    let result = tokio::select! {
+        result = timeout(wait, tcp.connect(target)) => result,
+        _ = claimed.notified() => return Ok(None),
+    };
+    let status = match result {
+        Ok(Ok(_)) => Status::Reply,
+        Ok(Err(err)) if err.kind() == io::ErrorKind::ConnectionRefused => Status::Refused,
+        Ok(Err(err)) => {
+            let _ = timeout(ICMP_GRACE, claimed.notified()).await;
+            // the proxy reports no route as unreachable, the rest as errors
+            return match ping.claim(&key) {
+                Some(_) => Err(err),
+                None => Ok(None),
+            };
+        }
+        Err(_) => {
+            ping.claim(&key);
+            return Ok(None);
+        }
+    };
+
+    if let Some(pending) = ping.claim(&key) {
+        let now = ping.elapsed().as_micros() as u64;
+        let mut reply = Reply::new(pending.seq, (now - pending.tx_time) as u32, 0);
+        reply.from = Some(request.host);
+        reply.status = status;
+        return Ok(Some(reply));
+    }
+
+    Ok(None)
+}
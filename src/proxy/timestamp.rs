@@ -0,0 +1,30 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use ping_proxy::proto::Request;
+
+use crate::ping::Ping;
+use crate::probe::{ProbeFuture, Prober};
+
+///
+/// An ICMP timestamp request asks the target for its clock, the reply
+/// comes in on the engine's IPv4 socket, or the raw Ethernet one like an
+/// echo reply, with the target's receive and transmit times and the client
+/// works out the clock offset from them.
+///
+pub struct TimestampProbe;
+
+impl Prober for TimestampProbe {
+    fn check(&self, ping: &Ping, request: &Request) -> Result<(), String> {
+        if request.vlan.is_some() && !ping.ether_enabled() {
+            return Err("VLAN tags need the proxy in raw Ethernet mode".to_string());
+        }
+        Ok(())
+    }
+
+    fn run(&self, ping: Arc<Ping>, source: SocketAddr, request: Request) -> ProbeFuture {
+        Box::pin(async move {
+            ping.send_timestamp(&source, &request).await?;
+            Ok(None)
+        })
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout, Duration},
+};
+
+use ping_proxy::proto::{Reply, Request};
+
+use crate::ping::{Ping, ProbeKey};
+use crate::probe::{ProbeFuture, Prober};
+use crate::sys;
+
+/// traceroute's classic base port, nothing listens there.
+pub const DEFAULT_PORT: u16 = 33434;
+
+///
+/// Every UDP probe owns an ephemeral socket, its local port identifies the
+/// probe in the UDP header quoted by ICMP errors, which the ICMP engine
+/// matches and answers. A datagram coming back on the socket is a reply.
+///
+pub struct UdpProbe;
+
+impl Prober for UdpProbe {
+    fn run(&self, ping: Arc<Ping>, source: SocketAddr, request: Request) -> ProbeFuture {
+        Box::pin(async move { udp_probe(&ping, &source, &request).await })
+    }
+}
+
+async fn udp_probe(
+    ping: &Ping,
+    source: &SocketAddr,
+    request: &Request,
+) -> io::Result<Option<Reply>> {
+    let bind = if request.host.is_ipv4() {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+    } else {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+    };
+    let udp = ping
+        .in_netns(move || {
+            let udp = std::net::UdpSocket::bind(bind)?;
+            udp.set_nonblocking(true)?;
+            UdpSocket::from_std(udp)
+        })
+        .await?;
+    let ipv4 = request.host.is_ipv4();
+    if let Some(ttl) = request.ttl {
+        sys::set_hop_limit(&udp, ipv4, ttl)?;
+    }
+    if let Some(tos) = request.tos {
+        sys::set_tos(&udp, ipv4, tos)?;
+    }
+    sys::set_recv_tos(&udp, ipv4)?;
+    if request.df {
+        sys::set_dont_fragment(&udp, ipv4)?;
+    }
+
+    let port = if request.port == 0 {
+        DEFAULT_PORT
+    } else {
+        request.port
+    };
+    let target = SocketAddr::new(request.host, port);
+    let key = ProbeKey::Udp(udp.local_addr()?.port());
+
+    let mut payload = vec![0u8; request.length as usize];
+    request.payload.fill(&mut payload);
+    let claimed = ping.register(key, source, request.seq, request.timeout);
+    if let Err(err) = udp.send_to(&payload, target).await {
+        ping.claim(&key);
+        return Err(err);
+    }
+
+    // the ICMP engine answers port unreachable and time exceeded itself
+    let mut buf = [0u8; 1500];
+    let wait = Duration::from_millis(request.timeout as u64);
+    let result = tokio::select! {
+        result = timeout(wait, sys::recv_from(&udp, &mut buf)) => result,
+        _ = claimed.notified() => return Ok(None),
+    };
+    let pending = ping.claim(&key);
+    if let (Ok(Ok(recv)), Some(pending)) = (result, pending) {
+        let now = ping.elapsed().as_micros() as u64;
+        let mut reply = Reply::new(pending.seq, (now - pending.tx_time) as u32, 0);
+        reply.from = Some(recv.from.ip());
+        reply.tos = recv.tos;
+        return Ok(Some(reply));
+    }
+
+    Ok(None)
+}